@@ -0,0 +1,26 @@
+use crate::mediawiki::{MediaWikiClient, ParseError};
+use crate::ZhVariant;
+
+/// Renders wikitext into HTML for `html_to_text` to extract the text from
+pub trait Backend: Send + Sync {
+    fn render(&self, wikitext: &str, variant: ZhVariant) -> Result<String, ParseError>;
+
+    /// Render the same wikitext into each of `variants`, in the same order.
+    /// Backends that can share work between variants should override this.
+    fn render_variants(
+        &self,
+        wikitext: &str,
+        variants: &[ZhVariant],
+    ) -> Vec<Result<String, ParseError>> {
+        variants
+            .iter()
+            .map(|&variant| self.render(wikitext, variant))
+            .collect()
+    }
+}
+
+impl Backend for MediaWikiClient {
+    fn render(&self, wikitext: &str, variant: ZhVariant) -> Result<String, ParseError> {
+        self.parse(wikitext, variant)
+    }
+}
@@ -0,0 +1,202 @@
+// Categories of pages read from `[[Category:…]]` links in the wikitext, and the category graph
+// built from the `Category:` namespace pages of a dump
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use regex::Regex;
+use serde::Serialize;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::sync::LazyLock;
+
+/// Namespace of category pages
+const CATEGORY_NAMESPACE: &str = "14";
+
+/// Prefixes of category links, English and the names of the namespace in every variant
+const CATEGORY_PREFIXES: &[&str] = &["category", "分类", "分類"];
+
+static COMMENT_OR_NOWIKI: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<!--.*?(-->|$)|<nowiki>.*?</nowiki>").unwrap());
+// A leading `:` as in `[[:Category:…]]` links to the category instead
+static CATEGORY_LINK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(
+        r"(?i)\[\[\s*(?:{})\s*:([^\[\]|]+)(?:\|[^\[\]]*)?\]\]",
+        CATEGORY_PREFIXES.join("|")
+    ))
+    .unwrap()
+});
+
+/// Names of the categories a page is in, in the order they are listed and without duplicates.
+/// Sort keys after `|` are removed and names are normalized with `normalize_name`, so the same
+/// category written with `Category:`, `分类:` or `分類:` and with spaces or underscores is the same.
+/// Names are not converted between simplified and traditional characters: like the titles of
+/// category pages, they are kept in the script they were written in, so they still match the
+/// names of the category graph.
+pub fn categories(wikitext: &str) -> Vec<String> {
+    let wikitext = COMMENT_OR_NOWIKI.replace_all(wikitext, "");
+    let mut categories: Vec<String> = vec![];
+    for caps in CATEGORY_LINK.captures_iter(&wikitext) {
+        let name = normalize_name(&caps[1]);
+        if !name.is_empty() && !categories.contains(&name) {
+            categories.push(name);
+        }
+    }
+    categories
+}
+
+/// Category name as in the title of its page without the namespace: single spaces instead of
+/// underscores and runs of whitespace, and an uppercase first letter
+fn normalize_name(name: &str) -> String {
+    let name = name.replace('_', " ");
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Name of the category whose page has `title`, `None` for pages of other namespaces
+fn category_name(title: &str) -> Option<String> {
+    let (prefix, name) = title.split_once(':')?;
+    CATEGORY_PREFIXES
+        .contains(&prefix.trim().to_lowercase().as_str())
+        .then(|| normalize_name(name))
+}
+
+/// A category of the category graph, written as one line of the output file
+#[derive(Debug, Serialize)]
+struct CategoryNode {
+    /// Page ID of the category page
+    id: i64,
+    name: String,
+    /// Categories the category is in
+    parents: Vec<String>,
+}
+
+/// Write a line for every category page of a dump with the categories it is in, so the category
+/// graph can be built from the parent edges. Redirected categories are skipped.
+pub fn extract_categories(xml_filename: &str, output_filename: &str) -> Result<(), Box<dyn Error>> {
+    let file = File::open(xml_filename)?;
+    let mut reader = Reader::from_reader(BufReader::new(file));
+    let mut writer = BufWriter::new(File::create(output_filename)?);
+
+    let mut buf = Vec::new();
+    let mut inside_ns = false;
+    let mut inside_title = false;
+    let mut inside_id = false;
+    let mut inside_revision = false;
+    let mut inside_text = false;
+    let mut is_category = false;
+    let mut is_redirect = false;
+    let mut page_id = None;
+    let mut title = String::new();
+    let mut text = String::new();
+    let mut count = 0;
+
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(ref e)) => match e.name().as_ref() {
+                b"page" => {
+                    is_category = false;
+                    is_redirect = false;
+                    page_id = None;
+                    title.clear();
+                    text.clear();
+                }
+                b"ns" => inside_ns = true,
+                b"title" => inside_title = true,
+                b"id" => inside_id = true,
+                b"revision" => inside_revision = true,
+                b"text" => inside_text = true,
+                _ => {}
+            },
+            Ok(Event::Empty(ref e)) if e.name().as_ref() == b"redirect" => is_redirect = true,
+            Ok(Event::End(ref e)) => match e.name().as_ref() {
+                b"page" => {
+                    let name = category_name(&title);
+                    if let (true, false, Some(id), Some(name)) =
+                        (is_category, is_redirect, page_id, name)
+                    {
+                        let node = CategoryNode {
+                            id,
+                            name,
+                            parents: categories(&text),
+                        };
+                        serde_json::to_writer(&mut writer, &node)?;
+                        writer.write_all(b"\n")?;
+                        count += 1;
+                    }
+                }
+                b"ns" => inside_ns = false,
+                b"title" => inside_title = false,
+                b"id" => inside_id = false,
+                b"revision" => inside_revision = false,
+                b"text" => inside_text = false,
+                _ => {}
+            },
+            Ok(Event::Text(e)) => {
+                let value = e.unescape()?;
+                if inside_ns {
+                    is_category = value == CATEGORY_NAMESPACE;
+                } else if inside_title {
+                    title.push_str(&value);
+                } else if inside_id && !inside_revision && page_id.is_none() {
+                    page_id = value.parse::<i64>().ok();
+                } else if inside_text && is_category {
+                    text.push_str(&value);
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(e.into()),
+            _ => {}
+        }
+        buf.clear();
+    }
+    writer.flush()?;
+    println!("{count} categories written to {output_filename}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sort_keys_and_aliases() {
+        assert_eq!(
+            categories(concat!(
+                "[[Category:中国城市|北京]] [[分类: 直辖市 ]] [[分類:首都]]\n",
+                "[[category:中国_城市]] [[Category:gIS|*]]",
+            )),
+            vec!["中国城市", "直辖市", "首都", "中国 城市", "GIS"]
+        );
+    }
+
+    #[test]
+    fn links_to_categories_are_ignored() {
+        assert_eq!(
+            categories("见[[:Category:城市]]和[[ :分类:首都|首都]]。[[Category:城市]]"),
+            vec!["城市"]
+        );
+    }
+
+    #[test]
+    fn comments_and_nowiki_are_ignored() {
+        assert_eq!(
+            categories(concat!(
+                "<!-- [[Category:注释]] -->[[Category:甲]]<nowiki>[[Category:乙]]</nowiki>",
+                "<NOWIKI>[[Category:丙]]</NOWIKI>[[分类:丁]]<!-- [[Category:未闭合]]",
+            )),
+            vec!["甲", "丁"]
+        );
+    }
+
+    #[test]
+    fn names_keep_their_script() {
+        assert_eq!(
+            categories("[[分类:中国城市]][[分類:中國城市]]"),
+            vec!["中国城市", "中國城市"]
+        );
+    }
+}
@@ -0,0 +1,205 @@
+// Infobox template calls read from the raw wikitext of a page, as the template name and its fields
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use crate::wikitext::{wikitext_to_html, COMMENT};
+use crate::zhconv::remove_markups;
+use crate::{clean_paragraphs, clean_text, html_to_blocks, Block, ElementKind};
+
+/// Infoboxes are named `Infobox …` in English or `…信息框`/`…資訊框` in Chinese
+const NAME_MARKERS: &[&str] = &["infobox", "信息框", "資訊框", "信息盒", "資訊盒"];
+
+static LINE_BREAK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)<br\s*/?>").unwrap());
+
+/// One `{{Infobox …}}` call of a page
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Infobox {
+    /// Template name without namespace, with underscores as spaces
+    pub name: String,
+    /// Cleaned text of each non-empty parameter, positional ones keyed by `1`, `2`, ...
+    /// Values with several lines or `<br>`s have one line per item.
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Infoboxes of a page in the order they start, including ones nested in another infobox
+pub fn infoboxes(wikitext: &str) -> Vec<Infobox> {
+    let wikitext = COMMENT.replace_all(wikitext, "");
+    let mut infoboxes = vec![];
+    let mut start = 0;
+    while let Some(i) = wikitext[start..].find("{{") {
+        let call = start + i;
+        // Continue inside the call so nested infoboxes are found too
+        start = call + 2;
+        // `{{{parameters}}}` only appear in templates
+        if wikitext[call..].starts_with("{{{") {
+            start = call + 3;
+            continue;
+        }
+        let Some(parts) = call_parts(&wikitext[call + 2..]) else {
+            continue;
+        };
+        let name = parts[0].trim().replace('_', " ");
+        let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+        let lowercase = name.to_lowercase();
+        if !NAME_MARKERS.iter().any(|marker| lowercase.contains(marker)) {
+            continue;
+        }
+        let mut fields = BTreeMap::new();
+        let mut position = 0;
+        for part in &parts[1..] {
+            let (key, value) = match split_named(part) {
+                Some((key, value)) => (key.trim().to_string(), value),
+                None => {
+                    position += 1;
+                    (position.to_string(), *part)
+                }
+            };
+            let value = clean_value(value);
+            if !key.is_empty() && !value.is_empty() {
+                fields.insert(key, value);
+            }
+        }
+        infoboxes.push(Infobox { name, fields });
+    }
+    infoboxes
+}
+
+/// Name and parameters of the template call whose `{{` precedes `text`, split on the `|`s that
+/// are not inside a nested template, link or `-{...}-` markup. `None` if the call is not closed.
+fn call_parts(text: &str) -> Option<Vec<&str>> {
+    let mut parts = vec![];
+    let mut braces = 0;
+    let mut brackets = 0;
+    let mut markups = 0;
+    let mut part_start = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        if rest.starts_with("-{") {
+            markups += 1;
+            i += 2;
+        } else if rest.starts_with("}-") && markups > 0 {
+            markups -= 1;
+            i += 2;
+        } else if rest.starts_with("{{") {
+            braces += 1;
+            i += 2;
+        } else if rest.starts_with("}}") {
+            if braces == 0 {
+                parts.push(&text[part_start..i]);
+                return Some(parts);
+            }
+            braces -= 1;
+            i += 2;
+        } else if rest.starts_with("[[") {
+            brackets += 1;
+            i += 2;
+        } else if rest.starts_with("]]") && brackets > 0 {
+            brackets -= 1;
+            i += 2;
+        } else {
+            if rest.starts_with('|') && braces == 0 && brackets == 0 && markups == 0 {
+                parts.push(&text[part_start..i]);
+                part_start = i + 1;
+            }
+            i += rest.chars().next().unwrap().len_utf8();
+        }
+    }
+    None
+}
+
+/// Split `key = value` at the first `=`, unless what comes before it is not a plain name: an `=`
+/// inside a nested template, link, tag, conversion markup or URL belongs to a positional value
+fn split_named(part: &str) -> Option<(&str, &str)> {
+    let equals = part.find('=')?;
+    let key = &part[..equals];
+    if ["{{", "[[", "-{", "<", ":"]
+        .iter()
+        .any(|markup| key.contains(markup))
+    {
+        return None;
+    }
+    Some((key, &part[equals + 1..]))
+}
+
+/// Render a parameter value like the text of the page and clean it the same way. Nested
+/// templates are removed, conversion markup shows its `zh` or first text, and list items and
+/// lines split by `<br>` are kept one per line.
+fn clean_value(value: &str) -> String {
+    let value = remove_markups(value, true);
+    let value = LINE_BREAK.replace_all(&value, "\n");
+    // Each line is rendered on its own, so it is a paragraph or list item of its own
+    let html: String = value
+        .lines()
+        .map(|line| wikitext_to_html(line.trim()))
+        .collect();
+    let elements = [ElementKind::Li, ElementKind::Dt, ElementKind::Dd];
+    // Values that are not valid HTML once rendered are left out
+    let Ok((blocks, _)) = html_to_blocks(&html, &elements) else {
+        return String::new();
+    };
+    blocks
+        .iter()
+        .flat_map(|block| match block {
+            Block::Text(text) => clean_paragraphs(text, false)
+                .lines()
+                .map(str::to_string)
+                .collect(),
+            Block::Element { text, .. } => vec![clean_text(text).trim().to_string()],
+            Block::Heading { .. } => vec![],
+        })
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(wikitext: &str) -> BTreeMap<String, String> {
+        let mut infoboxes = infoboxes(wikitext);
+        assert_eq!(infoboxes.len(), 1);
+        infoboxes.remove(0).fields
+    }
+
+    #[test]
+    fn values_keep_one_item_per_line() {
+        let fields = fields(concat!(
+            "{{Infobox city\n| name = '''北京'''<ref>x</ref>\n| leaders = 甲<br />乙\n",
+            "| districts =\n* 东城区\n* [[西城区]]\n| notes =\n; 面积 : 16410平方公里\n",
+            "| empty = \n}}",
+        ));
+        assert_eq!(fields["name"], "北京");
+        assert_eq!(fields["leaders"], "甲\n乙");
+        assert_eq!(fields["districts"], "东城区\n西城区");
+        assert_eq!(fields["notes"], "面积\n16410平方公里");
+        assert!(!fields.contains_key("empty"));
+    }
+
+    #[test]
+    fn conversion_markup_is_resolved() {
+        let fields = fields(concat!(
+            "{{信息框|1=-{zh-hans:计算机;zh-hant:電腦}-",
+            "|2=-{A|zh:電腦;zh-hans:计算机}-|3=-{R|a|b}-}}",
+        ));
+        assert_eq!(fields["1"], "计算机");
+        assert_eq!(fields["2"], "電腦");
+        assert_eq!(fields["3"], "a|b");
+    }
+
+    #[test]
+    fn positional_values_can_contain_equals_signs() {
+        let fields = fields(concat!(
+            "{{Infobox test|-{zh-hans:甲=乙;zh-hant:丙=丁}-|-{H|zh-cn:计算机=>zh-tw:電腦}-",
+            "|https://example.org/?q=1|<span title=x>值</span>|name=名}}",
+        ));
+        assert_eq!(fields["1"], "甲=乙");
+        assert!(!fields.contains_key("2"));
+        assert_eq!(fields["3"], "https://example.org/?q=1");
+        assert_eq!(fields["4"], "值");
+        assert_eq!(fields["name"], "名");
+    }
+}
@@ -1,208 +1,616 @@
-use core::num;
+use arrow_array::{Array, Int64Array};
+use clap::builder::RangedU64ValueParser;
+use clap::{Parser, Subcommand, ValueEnum};
+use crossbeam_channel::{bounded, Receiver, SendError, Sender};
 use itertools::Itertools;
 use kdam::{tqdm, BarExt};
-use parquet::column::writer::ColumnWriter;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::{ArrowWriter, ProjectionMask};
 use parquet::data_type::AsBytes;
-use parquet::record::RowAccessor;
-use parquet::{
-    data_type::ByteArray,
-    file::{properties::WriterProperties, writer::SerializedFileWriter},
-    schema::parser::parse_message_type,
-};
+use parquet::file::properties::WriterProperties;
 use quick_xml::events::Event;
 use quick_xml::Reader;
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter};
 use std::io::{Cursor, Write};
-use std::process::Command;
-use std::sync::{mpsc, Mutex};
-use std::thread::{self};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, LazyLock, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use std::{fs, path::Path, sync::Arc};
 
-#[derive(Debug, Deserialize)]
-struct Parse {
-    title: String,
-    pageid: i32,
-    text: Text,
+mod backend;
+mod category;
+mod infobox;
+mod mediawiki;
+mod reader;
+mod record;
+mod sentence;
+mod sink;
+mod span;
+mod table;
+mod template;
+mod tracked;
+mod wikitext;
+mod zhconv;
+
+use backend::Backend;
+use infobox::Infobox;
+use mediawiki::{MediaWikiClient, ParseError, RetryPolicy};
+use reader::{PageQuery, PageReader};
+use sink::{Compression, JsonlSink, Sink, WikiExtractorSink};
+use span::{link_target, Link, RawSpan, Span, SpanKind};
+use table::Table;
+use template::Templates;
+use tracked::TrackedText;
+use wikitext::NativeBackend;
+use zhconv::Converter;
+
+const MEDIAWIKI_XML_HEADER: &[u8; 252] = br###"<mediawiki xmlns="http://www.mediawiki.org/xml/export-0.10/" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://www.mediawiki.org/xml/export-0.10/ http://www.mediawiki.org/xml/export-0.10.xsd" version="0.10" xml:lang="zh">"###;
+
+#[derive(Debug, Parser)]
+#[command(about = "Extract clean Chinese text from zhwiki XML dumps")]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
 }
 
-#[derive(Debug, Deserialize)]
-struct Text {
-    #[serde(rename = "*")]
-    content: String,
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Copy all `Template:` pages of a dump into a standalone MediaWiki XML file
+    ExtractTemplates {
+        /// Path to the zhwiki pages-articles XML dump
+        input: String,
+        /// Path of the template XML file to write
+        #[arg(short, long, default_value = "data/zhwiki-templates.xml")]
+        output: String,
+    },
+    /// Write the category graph of a dump, one line per `Category:` page with the categories it is in
+    ExtractCategories {
+        /// Path to the zhwiki pages-articles XML dump
+        input: String,
+        /// Path of the JSON Lines file to write
+        #[arg(short, long, default_value = "data/zhwiki-categories.jsonl")]
+        output: String,
+    },
+    /// Split a template XML file into several files for parallel `importDump.php` runs
+    SplitTemplates {
+        /// Path to the template XML file produced by `extract-templates`
+        #[arg(default_value = "data/zhwiki-templates.xml")]
+        input: String,
+        /// Directory to write `zhwiki-templates-split-{n}.xml` files into
+        #[arg(short, long, default_value = "data")]
+        output_dir: String,
+        /// Number of files to split the templates into
+        #[arg(short, long, default_value_t = 10, value_parser = at_least_one())]
+        num_files: usize,
+    },
+    /// Parse articles and write the cleaned text to Parquet part files
+    Parse {
+        /// Path to the zhwiki pages-articles XML dump
+        input: String,
+        /// Directory to write the Parquet part files into, `{variant}` is replaced by each variant
+        #[arg(short, long, default_value = "wikipedia-{variant}")]
+        output: String,
+        /// Continue an interrupted run, skipping pages already committed to the output directory
+        #[arg(short, long)]
+        resume: bool,
+        /// Chinese variants to render articles in, separated by commas.
+        /// All variants are produced from a single read of the dump.
+        #[arg(short, long, value_enum, value_delimiter = ',', default_values_t = [ZhVariant::Tw])]
+        variant: Vec<ZhVariant>,
+        /// Drop lines that are too short or not mostly Chinese
+        #[arg(short, long)]
+        filter: bool,
+        /// Also keep the headings and paragraphs of each section in a `sections` column
+        #[arg(long)]
+        sections: bool,
+        /// Also keep the target, anchor text and offset in `content` of each wiki link
+        /// in a `links` column
+        #[arg(long)]
+        links: bool,
+        /// Also keep the bold, italic and link spans of `content`, as char offsets, in a `spans`
+        /// column
+        #[arg(long)]
+        spans: bool,
+        /// Also keep the name and fields of the infobox templates in the wikitext in an
+        /// `infoboxes` column
+        #[arg(long)]
+        infoboxes: bool,
+        /// Also keep the rows and cells of the tables in the rendered HTML in a `tables` column
+        #[arg(long)]
+        tables: bool,
+        /// Also keep the text of these block elements outside `<p>`s, separated by commas, in an
+        /// `elements` column
+        #[arg(long, value_delimiter = ',')]
+        elements: Vec<ElementKind>,
+        /// Also keep the names of the categories in the wikitext in a `categories` column
+        #[arg(long)]
+        categories: bool,
+        /// How to render wikitext into HTML
+        #[arg(long, value_enum, default_value_t = BackendKind::Mediawiki)]
+        backend: BackendKind,
+        /// Template XML file produced by `extract-templates` for the native backend to expand
+        /// templates with, otherwise templates are removed
+        #[arg(long)]
+        templates: Option<String>,
+        /// MediaWiki's `ZhConversion.php` for the native backend to convert text into the variant
+        #[arg(long)]
+        zh_conversion: Option<String>,
+        /// Number of worker threads parsing articles
+        #[arg(short, long, default_value_t = 20, value_parser = at_least_one())]
+        workers: usize,
+        /// Maximum number of pages waiting for a free worker, defaults to 4 per worker.
+        /// Reading the dump pauses while the queue is full.
+        #[arg(short, long, value_parser = at_least_one())]
+        queue_size: Option<usize>,
+        /// Order of the pages in the output
+        #[arg(long, value_enum, default_value_t = OutputOrder::Arrival)]
+        order: OutputOrder,
+        /// Number of pages per Parquet row group
+        #[arg(short, long, default_value_t = 1000, value_parser = at_least_one())]
+        batch_size: usize,
+        /// File format of the output
+        #[arg(long, value_enum, default_value_t = OutputFormat::Parquet)]
+        format: OutputFormat,
+        /// Whether to write a record per page or per sentence
+        #[arg(long, value_enum, default_value_t = Granularity::Paragraph)]
+        granularity: Granularity,
+        /// Compression of JSON Lines and wikiextractor shards
+        #[arg(long, value_enum, default_value_t = Compression::None)]
+        compression: Compression,
+        /// Uncompressed size in MB after which a new shard is started,
+        /// defaults to 256 for JSON Lines and 1 for wikiextractor like wikiextractor's `--bytes`
+        #[arg(long)]
+        shard_size_mb: Option<u64>,
+        /// URL prefix of the `url` attribute of wikiextractor `<doc>`s, followed by `?curid={id}`
+        #[arg(long, default_value = "https://zh.wikipedia.org/wiki")]
+        url_base: String,
+        /// URL of the MediaWiki `api.php` endpoint
+        #[arg(long, default_value = "http://localhost:8080/api.php")]
+        api_url: String,
+        /// Seconds to wait for MediaWiki to render a single request
+        #[arg(long, default_value_t = 300)]
+        timeout: u64,
+        /// Number of times to resend a request that failed with a retryable error
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+        /// Milliseconds to wait before the first retry, doubled after every further retry
+        #[arg(long, default_value_t = 1000)]
+        backoff_ms: u64,
+        /// Path of the JSON Lines file listing pages that failed to parse, `{variant}` is replaced
+        /// by each variant. Defaults to `failures.jsonl` in the output directory for Parquet, and
+        /// to `{output}.failures.jsonl` next to the shards of the other formats.
+        #[arg(long)]
+        failures: Option<String>,
+    },
+    /// Print pages stored in a Parquet file, which must have one record per page
+    Inspect {
+        /// Path to a Parquet file or a directory of part files written by `parse`
+        input: String,
+        /// Only print pages with these IDs
+        #[arg(short, long, value_delimiter = ',')]
+        ids: Option<Vec<i64>>,
+        /// Only print pages with these titles
+        #[arg(short, long)]
+        title: Option<Vec<String>>,
+        /// Only print pages edited at or after this date or RFC 3339 time
+        #[arg(long, value_parser = parse_timestamp)]
+        since: Option<i64>,
+        /// Only print pages edited before this date or RFC 3339 time
+        #[arg(long, value_parser = parse_timestamp)]
+        until: Option<i64>,
+        /// Only read these columns, e.g. `id,title`
+        #[arg(short, long, value_delimiter = ',')]
+        columns: Option<Vec<String>>,
+    },
+    /// Count the pages in an XML dump
+    Count {
+        /// Path to the XML dump
+        input: String,
+        /// Only count main namespace articles that are not redirects
+        #[arg(short, long)]
+        articles_only: bool,
+    },
 }
 
-#[derive(Debug, Deserialize)]
-struct ParseResponse {
-    parse: Parse,
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum BackendKind {
+    /// Send wikitext to the `action=parse` API of a MediaWiki server
+    Mediawiki,
+    /// Render wikitext in-process without a server
+    Native,
 }
 
-const MEDIAWIKI_XML_HEADER: &[u8; 252] = br###"<mediawiki xmlns="http://www.mediawiki.org/xml/export-0.10/" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://www.mediawiki.org/xml/export-0.10/ http://www.mediawiki.org/xml/export-0.10.xsd" version="0.10" xml:lang="zh">"###;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Parquet part files with a checkpoint, which can be resumed
+    Parquet,
+    /// JSON Lines shards with one page per line
+    Jsonl,
+    /// `<doc>` blocks in `AA/wiki_00`-style shards like attardi's wikiextractor
+    Wikiextractor,
+}
 
-fn main() {
-    // let xml_filename = "zhwiki-latest-pages-articles.xml";
-    let xml_filename = "zhwiki-short.xml";
-    // extract_templates(xml_filename, "data/zhwiki-templates.xml").unwrap();
-    split_templates("data/zhwiki-templates.xml").unwrap();
-    // parse_articles(xml_filename, ZhVariant::Tw, false).unwrap();
-
-    // // ids = HashSet::from_iter([45, 550, 672, 690, 758])
-    // let pages = read_from_parquet("wikipedia-zh-tw.parquet", None).unwrap();
-    // for page in pages {
-    //     println!("{page:#?}");
-    // }
-}
-
-fn request_parse(text: &str, variant: ZhVariant) -> Option<String> {
-    // Base URL and parameters
-    let base_url = "http://localhost:8080/api.php";
-    let lang = format!("uselang={variant}");
-    let text_arg = format!("text={text}");
-    let curl_args = vec![
-        "-X",
-        "POST",
-        base_url,
-        "--data-urlencode",
-        "action=parse",
-        "--data-urlencode",
-        "format=json",
-        "--data-urlencode",
-        "contentmodel=wikitext",
-        "--data-urlencode",
-        &lang,
-        "--data-urlencode",
-        &text_arg,
-    ];
-
-    // Use curl to make the request
-    match Command::new("curl").args(&curl_args).output() {
-        Ok(output) => {
-            // Convert the output to a string
-            let output_str = String::from_utf8(output.stdout).unwrap();
-
-            // Parse the JSON using serde_json
-            match serde_json::from_str::<ParseResponse>(&output_str) {
-                Ok(res) => {
-                    // Extract HTML from JSON response
-                    Some(res.parse.text.content.to_string())
-                }
-                Err(e) => {
-                    eprintln!("Failed to parse response JSON: {}", e);
-                    eprintln!("{output_str}");
-                    None
-                }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Granularity {
+    /// One record per page, with a paragraph per line of `content`
+    Paragraph,
+    /// One record per sentence, with the page ID and paragraph index
+    Sentence,
+}
+
+/// Block elements outside `<p>` that can be extracted with `--elements`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ElementKind {
+    /// List items of `<ul>` and `<ol>`
+    Li,
+    /// Terms of `<dl>`
+    Dt,
+    /// Definitions of `<dl>`, also used for indented text
+    Dd,
+    Blockquote,
+}
+
+impl ElementKind {
+    fn from_tag(tag_name: &str) -> Option<ElementKind> {
+        match tag_name {
+            "li" => Some(ElementKind::Li),
+            "dt" => Some(ElementKind::Dt),
+            "dd" => Some(ElementKind::Dd),
+            "blockquote" => Some(ElementKind::Blockquote),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputOrder {
+    /// Write pages as soon as they are parsed
+    Arrival,
+    /// Write pages in the order they appear in the dump, so runs are reproducible
+    Dump,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    match cli.command {
+        Commands::ExtractTemplates { input, output } => extract_templates(&input, &output)?,
+        Commands::ExtractCategories { input, output } => {
+            category::extract_categories(&input, &output)?
+        }
+        Commands::SplitTemplates {
+            input,
+            output_dir,
+            num_files,
+        } => split_templates(&input, &output_dir, num_files)?,
+        Commands::Parse {
+            input,
+            output,
+            resume,
+            variant,
+            filter,
+            sections,
+            links,
+            spans,
+            infoboxes,
+            tables,
+            elements,
+            categories,
+            backend,
+            templates,
+            zh_conversion,
+            workers,
+            queue_size,
+            order,
+            batch_size,
+            format,
+            granularity,
+            compression,
+            shard_size_mb,
+            url_base,
+            api_url,
+            timeout,
+            retries,
+            backoff_ms,
+            failures,
+        } => {
+            if resume && format != OutputFormat::Parquet {
+                return Err("--resume is only supported with --format parquet".into());
             }
+            let variants: Vec<ZhVariant> = variant.into_iter().unique().collect();
+            if variants.len() > 1 && !output.contains("{variant}") {
+                return Err("--output must contain {variant} when parsing several variants".into());
+            }
+            // Keep the shard directory to shards only, so the shards can be globbed
+            let failures = failures.unwrap_or_else(|| match format {
+                OutputFormat::Parquet => format!("{output}/failures.jsonl"),
+                _ => format!("{}.failures.jsonl", output.trim_end_matches('/')),
+            });
+            let retry = RetryPolicy {
+                max_retries: retries,
+                initial_backoff: Duration::from_millis(backoff_ms),
+                max_backoff: Duration::from_secs(60),
+            };
+            let backend: Arc<dyn Backend> = match backend {
+                BackendKind::Mediawiki => Arc::new(MediaWikiClient::new(
+                    &api_url,
+                    Duration::from_secs(timeout),
+                    workers,
+                    retry,
+                )),
+                BackendKind::Native => {
+                    let templates = match templates {
+                        Some(templates) => {
+                            let templates = Templates::load(&templates)?;
+                            println!("Loaded {} templates", templates.len());
+                            Some(templates)
+                        }
+                        None => None,
+                    };
+                    let converter = match zh_conversion {
+                        Some(zh_conversion) => Converter::load(&zh_conversion)?,
+                        None => Converter::default(),
+                    };
+                    Arc::new(NativeBackend::new(templates, converter))
+                }
+            };
+            let shard_size_mb = shard_size_mb.unwrap_or(match format {
+                OutputFormat::Wikiextractor => 1,
+                _ => 256,
+            });
+            let options = ParseOptions {
+                output_dir: output,
+                failures_filename: failures,
+                resume,
+                variants,
+                extract: ExtractOptions {
+                    filter,
+                    sections,
+                    links,
+                    spans,
+                    infoboxes,
+                    tables,
+                    elements,
+                    categories,
+                },
+                num_workers: workers,
+                queue_size: queue_size.unwrap_or(workers * 4),
+                order,
+                batch_size,
+                format,
+                granularity,
+                compression,
+                shard_size: shard_size_mb * 1024 * 1024,
+                url_base,
+            };
+            parse_articles(&input, backend, &options)?
         }
-        Err(err) => {
-            eprintln!("Curl command failed: {err}");
-            None
+        Commands::Inspect {
+            input,
+            ids,
+            title,
+            since,
+            until,
+            columns,
+        } => {
+            let query = PageQuery {
+                columns,
+                ids: ids.map(HashSet::from_iter),
+                titles: title.map(HashSet::from_iter),
+                since,
+                until,
+            };
+            for page in PageReader::new(parquet_files(&input)?, query) {
+                println!("{:#?}", page?);
+            }
         }
+        Commands::Count {
+            input,
+            articles_only,
+        } => println!("{}", count_pages(&input, articles_only)?),
     }
+    Ok(())
 }
 
-fn remove_tags(input: &str) -> String {
-    let paired_tags = fancy_regex::Regex::new(r"<\s*(\w+)\b.*?>.*?</\s*\1\s*>").unwrap();
-    let orphaned_open_tag = Regex::new(r"<\s*(\w+)\b.*?>").unwrap();
-    let orphaned_close_tag = Regex::new(r"</\s*(\w+)\s*>").unwrap();
-    let output = paired_tags.replace_all(input, "").to_string();
-    let output = orphaned_open_tag.replace_all(&output, "").to_string();
-    let output = orphaned_close_tag.replace_all(&output, "").to_string();
-    output
+static PAIRED_TAGS: LazyLock<fancy_regex::Regex> =
+    LazyLock::new(|| fancy_regex::Regex::new(r"<\s*(\w+)\b.*?>.*?</\s*\1\s*>").unwrap());
+static ORPHANED_OPEN_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<\s*(\w+)\b.*?>").unwrap());
+static ORPHANED_CLOSE_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"</\s*(\w+)\s*>").unwrap());
+static SELF_CLOSING_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<\w+\b.*?/>").unwrap());
+static SPACES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r" +").unwrap());
+static DOTS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\.{3,}").unwrap());
+static FULL_STOPS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"。{3,}").unwrap());
+static SPACE_BEFORE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r" ([,，:：\.。\)）\]】»》])").unwrap());
+static SPACE_AFTER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"([\[【\(（«《]) ").unwrap());
+static PUNCTUATION_LINE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\n\W+?\n").unwrap());
+static PUNCTUATION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\p{P}").unwrap());
+static HAN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\p{Han}").unwrap());
+static PARENTHESIZED_HAN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[（](\p{Han}+)[）]").unwrap());
+static PARENTHESIZED: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[（].*[）]").unwrap());
+static MARKED_HAN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"@@start@@(.+)@@end@@").unwrap());
+static SECTION_HEADER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"==.+==\s*\n?").unwrap());
+
+fn remove_tags(text: &mut TrackedText) {
+    text.replace_all_fancy(&PAIRED_TAGS, "");
+    text.replace_all(&ORPHANED_OPEN_TAG, "");
+    text.replace_all(&ORPHANED_CLOSE_TAG, "");
 }
 
-fn remove_self_closing_tags(input: &str) -> String {
-    let pattern = r"<\w+\b.*?/>";
-    let re = Regex::new(&pattern).unwrap();
-    re.replace_all(input, "").to_string()
+fn remove_self_closing_tags(text: &mut TrackedText) {
+    text.replace_all(&SELF_CLOSING_TAG, "");
 }
 
-// https://github.com/attardi/wikiextractor/blob/8f1b434a80608e1e313d38d263ed7c79c9ee75a9/wikiextractor/extract.py#L163
 fn clean_text(text: &str) -> String {
-    let mut text = text.to_string();
+    let mut text = TrackedText::new(text, false);
+    clean_tracked_text(&mut text);
+    text.text
+}
 
+// https://github.com/attardi/wikiextractor/blob/8f1b434a80608e1e313d38d263ed7c79c9ee75a9/wikiextractor/extract.py#L163
+fn clean_tracked_text(text: &mut TrackedText) {
     // Replace '<<' with '«' and '>>' with '»'
-    text = text.replace("<<", "«").replace(">>", "»");
+    text.replace("<<", "«");
+    text.replace(">>", "»");
 
     // Replace tabs with spaces
-    text = text.replace("\t", " ");
+    text.replace("\t", " ");
 
     // Replace multiple spaces with a single space
-    let re_spaces = Regex::new(r" +").unwrap();
-    text = re_spaces.replace_all(&text, " ").to_string();
+    text.replace_all(&SPACES, " ");
 
     // Replace multiple dots with '...'
-    let re_dots = Regex::new(r"\.{3,}").unwrap();
-    text = re_dots.replace_all(&text, "……").to_string();
-
-    let re_dots = Regex::new(r"。{3,}").unwrap();
-    text = re_dots.replace_all(&text, "……").to_string();
+    text.replace_all(&DOTS, "……");
+    text.replace_all(&FULL_STOPS, "……");
 
     // Handle other replacements
-    let re_before = Regex::new(r" ([,，:：\.。\)）\]】»》])").unwrap();
-    text = re_before.replace_all(&text, "$1").to_string();
-
-    let re_after = Regex::new(r"([\[【\(（«《]) ").unwrap();
-    text = re_after.replace_all(&text, "$1").to_string();
+    text.replace_all(&SPACE_BEFORE, "$1");
+    text.replace_all(&SPACE_AFTER, "$1");
 
     // Remove lines with only punctuations
-    let re_newlines = Regex::new(r"\n\W+?\n").unwrap();
-    text = re_newlines.replace_all(&text, "\n").to_string();
+    text.replace_all(&PUNCTUATION_LINE, "\n");
 
     // Replace ',,' with ',' and ',.' with '.'
-    text = text
-        .replace(",,", ",")
-        .replace("，，", "，")
-        .replace(",.", ".")
-        .replace("，。", "。");
+    text.replace(",,", ",");
+    text.replace("，，", "，");
+    text.replace(",.", ".");
+    text.replace("，。", "。");
+}
 
-    text
+fn filter_lines(text: &mut TrackedText) {
+    text.retain_lines(|line| {
+        line.chars().count() >= 10 // Filter out lines with fewer than 10 characters
+            && PUNCTUATION.find(line).is_some() // Filter out lines without any punctuations
+            // contains more than 70% of Chinese characters
+            && HAN.find_iter(line).count() as f64
+                > (line.chars().count() as f64) * 0.7
+    });
 }
 
-fn filter_lines(text: &str) -> String {
-    let punctuation = Regex::new(r"\p{P}").unwrap();
-    let han = Regex::new(r"\p{Han}").unwrap();
+/// Text of rendered HTML in document order, as headings and the raw text of the `<p>`s between them
+enum Block {
+    Heading {
+        level: u8,
+        text: String,
+    },
+    Text(String),
+    /// Raw text of an element extracted with `--elements`
+    Element {
+        kind: ElementKind,
+        text: String,
+    },
+}
 
-    text.lines()
-        .into_iter()
-        .map(|line| line.trim())
-        .filter(|&line| line.chars().count() >= 10) // Filter out lines with fewer than 10 characters
-        .filter(|&line| punctuation.find(line).is_some()) // Filter out lines without any punctuations
-        .filter(|&line|
-            // contains more than 70% of Chinese characters
-            line.chars().filter(|c| han.is_match(&c.to_string())).count() as f64 > (line.chars().count() as f64) * 0.7)
-        .join("\n")
+/// Classes of boxes whose lists are navigation or references rather than content
+const EXCLUDED_ELEMENT_CLASSES: &[&str] = &["navbox", "references", "reflist", "toc"];
+
+/// Level of an `<h2>` to `<h6>` heading
+fn heading_level(tag_name: &str) -> Option<u8> {
+    match tag_name {
+        "h2" => Some(2),
+        "h3" => Some(3),
+        "h4" => Some(4),
+        "h5" => Some(5),
+        "h6" => Some(6),
+        _ => None,
+    }
 }
 
-fn html_to_text(html: &str, filter: bool) -> String {
+/// Blocks of rendered HTML, with the bold, italic and link spans of its paragraphs in document order.
+/// `elements` are also kept, except inside tables and navigation or reference boxes.
+fn html_to_blocks(
+    html: &str,
+    elements: &[ElementKind],
+) -> Result<(Vec<Block>, Vec<RawSpan>), ParseError> {
+    // Text is not trimmed, so the spaces between inline elements are kept
     let mut reader = Reader::from_reader(Cursor::new(html));
-    reader.trim_text(true);
 
     let mut buf = Vec::new();
     let mut current_is_allowed_tag = true;
     let mut current_in_p = false;
+    let mut blocks = vec![];
     let mut output = String::new();
+    // Level and text of the heading being read
+    let mut heading: Option<(u8, String)> = None;
+    // Depth inside the `[编辑]` link MediaWiki adds to headings
+    let mut editsection_depth = 0;
+    let mut spans: Vec<RawSpan> = vec![];
+    // Spans whose end tag has not been read yet, by index in `spans`
+    let mut open_spans: Vec<usize> = vec![];
+    // Length of the text blocks before `output`, so span ranges are into all paragraphs
+    let mut flushed = 0;
+    // Index in `blocks` of the elements whose end tag has not been read yet
+    let mut open_elements: Vec<usize> = vec![];
+    // Depth inside a table or a box whose elements are not kept
+    let mut excluded_depth = 0;
 
     let allowed_tags: HashSet<&str> = ["b", "i", "a"].iter().cloned().collect();
 
     loop {
         match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(ref e)) if heading.is_some() => {
+                let is_editsection = e.attributes().flatten().any(|attribute| {
+                    attribute.key.as_ref() == b"class"
+                        && String::from_utf8_lossy(&attribute.value).contains("mw-editsection")
+                });
+                if editsection_depth > 0 || is_editsection {
+                    editsection_depth += 1;
+                }
+            }
             Ok(Event::Start(ref e)) => {
                 let name = e.name();
-                let tag_name = std::str::from_utf8(name.as_ref()).unwrap();
+                let tag_name: &str = &String::from_utf8_lossy(name.as_ref());
+                if excluded_depth > 0 {
+                    excluded_depth += 1;
+                } else if tag_name == "table"
+                    || e.attributes().flatten().any(|attribute| {
+                        let value = String::from_utf8_lossy(&attribute.value);
+                        attribute.key.as_ref() == b"class"
+                            && EXCLUDED_ELEMENT_CLASSES
+                                .iter()
+                                .any(|class| value.contains(class))
+                    })
+                {
+                    excluded_depth = 1;
+                } else if let Some(kind) =
+                    ElementKind::from_tag(tag_name).filter(|kind| elements.contains(kind))
+                {
+                    open_elements.push(blocks.len());
+                    blocks.push(Block::Element {
+                        kind,
+                        text: String::new(),
+                    });
+                }
+                if let Some(level) = heading_level(tag_name) {
+                    if !output.is_empty() {
+                        flushed += output.len();
+                        blocks.push(Block::Text(std::mem::take(&mut output)));
+                    }
+                    heading = Some((level, String::new()));
+                }
                 if tag_name == "p" {
                     current_in_p = true;
                 }
                 if current_in_p && allowed_tags.contains(tag_name) {
                     current_is_allowed_tag = true;
+                    let mut target = None;
                     if tag_name == "a" {
                         for attr in e.attributes() {
                             match attr {
                                 Ok(ref attribute) if attribute.key.as_ref() == b"href" => {
-                                    let value = std::str::from_utf8(&attribute.value).unwrap();
+                                    let value = String::from_utf8_lossy(&attribute.value);
                                     if value.starts_with("/index.php?title=Template:")
                                         || value.starts_with("/index.php?title=API")
                                         || value.starts_with("/index.php/File:")
@@ -210,80 +618,284 @@ fn html_to_text(html: &str, filter: bool) -> String {
                                         // Skip this <a> tag
                                         current_is_allowed_tag = false;
                                     }
+                                    target = attribute
+                                        .unescape_value()
+                                        .ok()
+                                        .and_then(|href| link_target(&href));
                                 }
                                 _ => {}
                             }
                         }
                     }
+                    let kind = match tag_name {
+                        "b" => Some(SpanKind::Bold),
+                        "i" => Some(SpanKind::Italic),
+                        // External links and anchors are not spans
+                        _ => target.is_some().then_some(SpanKind::Link),
+                    };
+                    if let Some(kind) = kind.filter(|_| current_is_allowed_tag) {
+                        let start = flushed + output.len();
+                        spans.push(RawSpan {
+                            kind,
+                            target,
+                            range: start..start,
+                        });
+                        open_spans.push(spans.len() - 1);
+                    }
                 }
             }
+            Ok(Event::End(_)) if editsection_depth > 0 => editsection_depth -= 1,
             Ok(Event::End(ref e)) => {
                 let name = e.name();
-                let tag_name = std::str::from_utf8(name.as_ref()).unwrap();
+                let tag_name: &str = &String::from_utf8_lossy(name.as_ref());
+
+                if excluded_depth > 0 {
+                    excluded_depth -= 1;
+                } else if let Some(&i) = open_elements.last() {
+                    match &mut blocks[i] {
+                        Block::Element { kind, .. }
+                            if ElementKind::from_tag(tag_name) == Some(*kind) =>
+                        {
+                            open_elements.pop();
+                        }
+                        // Paragraphs in an element are kept on separate lines
+                        Block::Element { text, .. } if tag_name == "p" => text.push('\n'),
+                        _ => {}
+                    }
+                }
+
+                if heading_level(tag_name).is_some() {
+                    if let Some((level, text)) = heading.take() {
+                        blocks.push(Block::Heading { level, text });
+                    }
+                }
 
                 if current_in_p && allowed_tags.contains(tag_name) {
                     current_is_allowed_tag = true;
+                    let kind = match tag_name {
+                        "b" => SpanKind::Bold,
+                        "i" => SpanKind::Italic,
+                        _ => SpanKind::Link,
+                    };
+                    // Skipped and external links were never opened
+                    if let Some(&i) = open_spans.last() {
+                        if spans[i].kind == kind {
+                            open_spans.pop();
+                            spans[i].range.end = flushed + output.len();
+                        }
+                    }
                 }
 
                 if tag_name == "p" {
-                    if !output.is_empty() && !output.ends_with("\n") {
+                    if !output.is_empty() && !output.ends_with('\n') {
                         output.push('\n');
                     }
                     current_in_p = false;
                 }
             }
             Ok(Event::Text(e)) => {
-                let text = e.unescape().unwrap();
-                if current_in_p && current_is_allowed_tag {
-                    output.push_str(&text);
+                let text = e.unescape().map_err(|e| {
+                    ParseError::Html(format!("at position {}: {e}", reader.buffer_position()))
+                })?;
+                if let Some((_, heading)) = &mut heading {
+                    if editsection_depth == 0 {
+                        heading.push_str(&text);
+                    }
+                } else if current_is_allowed_tag {
+                    if current_in_p {
+                        output.push_str(&text);
+                    }
+                    if let Some(&i) = open_elements.last() {
+                        if let Block::Element { text: element, .. } = &mut blocks[i] {
+                            element.push_str(&text);
+                        }
+                    }
                 }
             }
             Ok(Event::Eof) => break,
-            Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
+            Err(e) => {
+                return Err(ParseError::Html(format!(
+                    "at position {}: {e}",
+                    reader.buffer_position()
+                )))
+            }
             _ => (),
         }
         buf.clear();
     }
-    output = remove_tags(&output);
-    output = remove_self_closing_tags(&output);
+    if !output.is_empty() {
+        blocks.push(Block::Text(output));
+    }
+    Ok((blocks, spans))
+}
+
+/// Clean up the raw text of `<p>`s into one paragraph per line
+fn clean_paragraphs(text: &str, filter: bool) -> String {
+    let mut text = TrackedText::new(text, false);
+    clean_tracked_paragraphs(&mut text, filter);
+    text.text
+}
+
+/// Clean up the raw text of `<p>`s like `clean_paragraphs`, keeping track of the origins if
+/// `text` tracks them
+fn clean_tracked_paragraphs(text: &mut TrackedText, filter: bool) {
+    remove_tags(text);
+    remove_self_closing_tags(text);
 
     // Remove messy parenthesized expressions
-    let parenthesized_han = Regex::new(r"[（](\p{Han}+)[）]").unwrap();
-    output = parenthesized_han
-        .replace_all(&output, "@@start@@$1@@end@@")
-        .to_string();
-    let parenthesized = Regex::new(r"[（].*[）]").unwrap();
-    output = parenthesized.replace_all(&output, "").to_string();
-    let parenthesized_han = Regex::new(r"@@start@@(.+)@@end@@").unwrap();
-    output = parenthesized_han.replace_all(&output, "（$1）").to_string();
-    output = output.replace("@@start@@", "（").replace("@@end@@", "）");
+    text.replace_all(&PARENTHESIZED_HAN, "@@start@@$1@@end@@");
+    text.replace_all(&PARENTHESIZED, "");
+    text.replace_all(&MARKED_HAN, "（$1）");
+    text.replace("@@start@@", "（");
+    text.replace("@@end@@", "）");
 
     // Remove section headers
-    let section_header = Regex::new(r"==.+==\s*\n?").unwrap();
-    output = section_header.replace_all(&output, "").to_string();
+    text.replace_all(&SECTION_HEADER, "");
 
-    output = clean_text(&output);
+    clean_tracked_text(text);
 
     if filter {
-        output = filter_lines(&output);
+        filter_lines(text);
     }
 
-    output.trim().to_string()
+    text.trim();
 }
 
-#[derive(Debug, Clone, Copy)]
+fn html_to_text(html: &str, filter: bool) -> Result<String, ParseError> {
+    Ok(blocks_to_text(&html_to_blocks(html, &[])?.0, filter, false).text)
+}
+
+/// Cleaned text of the paragraphs before the first heading
+fn blocks_to_abstract(blocks: &[Block], filter: bool) -> String {
+    let text: String = blocks
+        .iter()
+        .take_while(|block| !matches!(block, Block::Heading { .. }))
+        .filter_map(|block| match block {
+            Block::Text(text) => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+    clean_paragraphs(&text, filter)
+}
+
+/// Cleaned text of all paragraphs, ignoring the headings. With `track`, the text keeps where
+/// each byte was in the raw text of the paragraphs, which spans are located with.
+fn blocks_to_text(blocks: &[Block], filter: bool, track: bool) -> TrackedText {
+    let text: String = blocks
+        .iter()
+        .filter_map(|block| match block {
+            Block::Text(text) => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+    let mut text = TrackedText::new(&text, track);
+    clean_tracked_paragraphs(&mut text, filter);
+    text
+}
+
+/// A heading and the paragraphs up to the next heading. The lead section before the first
+/// heading has level 0 and an empty heading.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Section {
+    heading: String,
+    level: u8,
+    paragraphs: Vec<String>,
+}
+
+fn blocks_to_sections(blocks: &[Block], filter: bool) -> Vec<Section> {
+    let mut sections = vec![Section {
+        heading: String::new(),
+        level: 0,
+        paragraphs: vec![],
+    }];
+    for block in blocks {
+        match block {
+            Block::Heading { level, text } => sections.push(Section {
+                heading: clean_text(text).trim().to_string(),
+                level: *level,
+                paragraphs: vec![],
+            }),
+            Block::Text(text) => {
+                let paragraphs = clean_paragraphs(text, filter);
+                sections
+                    .last_mut()
+                    .unwrap()
+                    .paragraphs
+                    .extend(paragraphs.lines().map(str::to_string));
+            }
+            Block::Element { .. } => {}
+        }
+    }
+    // Keep empty sections like "参考文献" since their heading is still useful, but not an empty lead
+    if sections[0].paragraphs.is_empty() {
+        sections.remove(0);
+    }
+    sections
+}
+
+/// A block element extracted with `--elements`, in the section it appears in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Element {
+    kind: ElementKind,
+    /// Heading of the section, empty in the lead section
+    heading: String,
+    text: String,
+}
+
+/// Cleaned text of the extracted elements, dropping ones that are empty after cleaning
+fn blocks_to_elements(blocks: &[Block]) -> Vec<Element> {
+    let mut heading = String::new();
+    let mut elements = vec![];
+    for block in blocks {
+        match block {
+            Block::Heading { text, .. } => heading = clean_text(text).trim().to_string(),
+            Block::Element { kind, text } => {
+                let text = clean_paragraphs(text, false);
+                if !text.is_empty() {
+                    elements.push(Element {
+                        kind: *kind,
+                        heading: heading.clone(),
+                        text,
+                    });
+                }
+            }
+            Block::Text(_) => {}
+        }
+    }
+    elements
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
 enum ZhVariant {
+    /// Original text without any conversion
+    #[value(name = "zh")]
+    Zh,
+    /// Simplified characters without regional vocabulary
+    #[value(name = "zh-hans")]
+    Hans,
+    /// Traditional characters without regional vocabulary
+    #[value(name = "zh-hant")]
+    Hant,
+    #[value(name = "zh-cn")]
     Cn,
+    #[value(name = "zh-hk")]
     Hk,
+    #[value(name = "zh-mo")]
     Mo,
+    #[value(name = "zh-my")]
     My,
+    #[value(name = "zh-sg")]
     Sg,
+    #[value(name = "zh-tw")]
     Tw,
 }
 
 impl std::fmt::Display for ZhVariant {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
+            ZhVariant::Zh => write!(f, "zh"),
+            ZhVariant::Hans => write!(f, "zh-hans"),
+            ZhVariant::Hant => write!(f, "zh-hant"),
             ZhVariant::Cn => write!(f, "zh-cn"),
             ZhVariant::Hk => write!(f, "zh-hk"),
             ZhVariant::Mo => write!(f, "zh-mo"),
@@ -294,30 +906,143 @@ impl std::fmt::Display for ZhVariant {
     }
 }
 
-#[derive(Debug, Clone)]
-struct Page {
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Page {
+    #[serde(rename = "id")]
     page_id: i64,
     revision_id: i64,
     timestamp: i64,
     title: String,
     content: String,
+    /// Cleaned text of the lead section before the first heading
+    r#abstract: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sections: Option<Vec<Section>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    links: Option<Vec<Link>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spans: Option<Vec<Span>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    infoboxes: Option<Vec<Infobox>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tables: Option<Vec<Table>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    elements: Option<Vec<Element>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    categories: Option<Vec<String>>,
+}
+
+/// What `parse_page` extracts from the rendered HTML besides the text
+#[derive(Debug, Clone, Default)]
+struct ExtractOptions {
+    /// Drop lines that are too short or not mostly Chinese
+    filter: bool,
+    sections: bool,
+    links: bool,
+    spans: bool,
+    infoboxes: bool,
+    tables: bool,
+    elements: Vec<ElementKind>,
+    categories: bool,
+}
+
+/// A page that could not be parsed, written as one line of the failures file
+#[derive(Debug, Serialize)]
+struct Failure<'a> {
+    page_id: i64,
+    title: &'a str,
+    kind: &'static str,
+    error: String,
+}
+
+/// Render the title and text of a page into each of `variants` with `backend` and clean them up.
+/// A variant is `None` if either the title or the text is empty after cleaning.
+fn parse_page(
+    backend: &dyn Backend,
+    page: &Page,
+    variants: &[ZhVariant],
+    extract: &ExtractOptions,
+) -> Vec<Result<Option<Page>, ParseError>> {
+    let titles: Vec<Result<String, ParseError>> = backend
+        .render_variants(&page.title, variants)
+        .into_iter()
+        .map(|html_title| html_title.and_then(|html_title| html_to_text(&html_title, false)))
+        .collect();
+
+    // Only render the text in variants with a title
+    let text_variants: Vec<ZhVariant> = variants
+        .iter()
+        .zip(&titles)
+        .filter(|(_, title)| matches!(title, Ok(title) if !title.is_empty()))
+        .map(|(variant, _)| *variant)
+        .collect();
+    let mut html_texts = backend
+        .render_variants(&page.content, &text_variants)
+        .into_iter();
+    // Read from the wikitext, so the same for every variant
+    let infoboxes = extract.infoboxes.then(|| infobox::infoboxes(&page.content));
+    let categories = extract
+        .categories
+        .then(|| category::categories(&page.content));
+
+    titles
+        .into_iter()
+        .map(|title| {
+            let title = title?;
+            if title.is_empty() {
+                return Ok(None);
+            }
+            let html_text = html_texts.next().ok_or_else(|| {
+                ParseError::Html("the backend rendered fewer variants than asked".to_string())
+            })??;
+            let (blocks, spans) = html_to_blocks(&html_text, &extract.elements)?;
+            let cleaned_text =
+                blocks_to_text(&blocks, extract.filter, extract.links || extract.spans);
+            if cleaned_text.text.is_empty() {
+                return Ok(None);
+            }
+            let links = extract.links.then(|| span::links(&cleaned_text, &spans));
+            let spans = extract.spans.then(|| span::spans(&cleaned_text, &spans));
+            let tables = extract.tables.then(|| table::tables(&html_text));
+            let elements = (!extract.elements.is_empty()).then(|| blocks_to_elements(&blocks));
+            Ok(Some(Page {
+                page_id: page.page_id,
+                revision_id: page.revision_id,
+                timestamp: page.timestamp,
+                title,
+                content: cleaned_text.text,
+                r#abstract: blocks_to_abstract(&blocks, extract.filter),
+                sections: extract
+                    .sections
+                    .then(|| blocks_to_sections(&blocks, extract.filter)),
+                links,
+                spans,
+                infoboxes: infoboxes.clone(),
+                tables,
+                elements,
+                categories: categories.clone(),
+            }))
+        })
+        .collect()
 }
 
-fn split_templates(templates_filename: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn split_templates(
+    templates_filename: &str,
+    output_dir: &str,
+    num_files: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
     let num_pages = count_pages(templates_filename, false)?;
-    let pages_per_file = (num_pages as f32 / 10.0).ceil() as usize; // Calculate how many pages per file, always rounding up
+    let pages_per_file = (num_pages as f32 / num_files as f32).ceil() as usize; // Calculate how many pages per file, always rounding up
     println!("pages_per_file: {pages_per_file}");
 
     let mut reader = Reader::from_file(templates_filename)?;
-    reader.trim_text(true);
 
     let mut buf = Vec::new();
     let mut file_count = 0;
     let mut page_count = 0;
-    let mut writer = BufWriter::new(File::create(format!(
-        "data/zhwiki-templates-split-{}.xml",
-        file_count
-    ))?);
+    let mut writer = BufWriter::new(File::create(
+        Path::new(output_dir).join(format!("zhwiki-templates-split-{}.xml", file_count)),
+    )?);
 
     // The header for the first file is included in the source template file
 
@@ -331,10 +1056,10 @@ fn split_templates(templates_filename: &str) -> Result<(), Box<dyn std::error::E
                     file_count += 1;
                     page_count = 0;
 
-                    writer = BufWriter::new(File::create(format!(
-                        "data/zhwiki-templates-split-{}.xml",
-                        file_count
-                    ))?);
+                    writer = BufWriter::new(File::create(
+                        Path::new(output_dir)
+                            .join(format!("zhwiki-templates-split-{}.xml", file_count)),
+                    )?);
                     writer.write_all(MEDIAWIKI_XML_HEADER)?;
                 }
 
@@ -469,12 +1194,9 @@ fn count_pages(xml_filename: &str, only_articles: bool) -> quick_xml::Result<usi
     let mut inside_ns = false;
     loop {
         match reader.read_event_into(&mut buf) {
-            Ok(Event::Start(ref e)) => match e.name().as_ref() {
-                b"ns" => {
-                    inside_ns = true;
-                }
-                _ => {}
-            },
+            Ok(Event::Start(ref e)) if e.name().as_ref() == b"ns" => {
+                inside_ns = true;
+            }
             Ok(Event::End(ref e)) => match e.name().as_ref() {
                 b"page" => {
                     if !only_articles || is_article {
@@ -495,12 +1217,9 @@ fn count_pages(xml_filename: &str, only_articles: bool) -> quick_xml::Result<usi
                 }
             }
             Ok(Event::Eof) => break, // Exit the loop when reaching end of file
-            Ok(Event::Empty(ref e)) => match e.name().as_ref() {
-                b"redirect" => {
-                    is_article = false;
-                }
-                _ => {}
-            },
+            Ok(Event::Empty(ref e)) if e.name().as_ref() == b"redirect" => {
+                is_article = false;
+            }
             Err(e) => return Err(e),
             _ => (), // There are several other Event variants that we do not handle here
         }
@@ -509,46 +1228,281 @@ fn count_pages(xml_filename: &str, only_articles: bool) -> quick_xml::Result<usi
     Ok(count)
 }
 
+/// Settings of a `parse_articles` run
+#[derive(Debug, Clone)]
+struct ParseOptions {
+    /// Output directory, with `{variant}` replaced by each variant
+    output_dir: String,
+    /// Failures file, with `{variant}` replaced by each variant
+    failures_filename: String,
+    resume: bool,
+    variants: Vec<ZhVariant>,
+    extract: ExtractOptions,
+    num_workers: usize,
+    queue_size: usize,
+    order: OutputOrder,
+    batch_size: usize,
+    format: OutputFormat,
+    granularity: Granularity,
+    compression: Compression,
+    /// Uncompressed bytes per JSON Lines or wikiextractor shard
+    shard_size: u64,
+    url_base: String,
+}
+
+/// Most pages the reader gets ahead of the oldest page not written yet in dump order, so a slow
+/// page holds back a bounded number of finished pages
+const MAX_REORDER_WINDOW: u64 = 10_000;
+
+/// Holds pages parsed out of order until all pages read before them are done
+#[derive(Debug, Default)]
+struct ReorderBuffer {
+    /// Sequence number of the next page to release
+    next: u64,
+    /// Finished pages waiting for earlier ones, `None` if the page produced no output
+    waiting: BTreeMap<u64, Option<Page>>,
+}
+
+impl ReorderBuffer {
+    /// Add the page with sequence number `seq` and return the pages that can be written now
+    fn insert(&mut self, seq: u64, page: Option<Page>) -> Vec<Page> {
+        self.waiting.insert(seq, page);
+        let mut ready = vec![];
+        while let Some(page) = self.waiting.remove(&self.next) {
+            ready.extend(page);
+            self.next += 1;
+        }
+        ready
+    }
+}
+
+/// Where `parse_articles` writes the pages of one variant
+struct VariantOutput {
+    variant: ZhVariant,
+    /// Parsed pages go to the writer thread, which owns the `PartWriter`
+    pages: Sender<Page>,
+    writer: JoinHandle<Result<(), String>>,
+    /// Only used when writing in dump order
+    reorder: Option<Mutex<ReorderBuffer>>,
+    /// Signalled whenever the reorder buffer releases pages
+    released: Condvar,
+    /// Pages that failed to parse are recorded in a side file
+    failures: Mutex<BufWriter<File>>,
+    /// First error writing the failures file, reported by `finish`
+    failures_error: Mutex<Option<std::io::Error>>,
+    failures_filename: String,
+    num_failures: Mutex<usize>,
+    /// Pages already written by an interrupted run
+    committed: HashSet<i64>,
+}
+
+impl VariantOutput {
+    fn open(
+        variant: ZhVariant,
+        options: &ParseOptions,
+    ) -> Result<VariantOutput, Box<dyn std::error::Error>> {
+        let resume = options.resume;
+        let output_dir = options
+            .output_dir
+            .replace("{variant}", &variant.to_string());
+        let failures_filename = options
+            .failures_filename
+            .replace("{variant}", &variant.to_string());
+
+        let (sink, committed): (Box<dyn Sink>, HashSet<i64>) = match options.format {
+            OutputFormat::Parquet => {
+                let (writer, committed) =
+                    PartWriter::open(Path::new(&output_dir), options.granularity, resume)?;
+                (Box::new(writer), committed)
+            }
+            OutputFormat::Jsonl => {
+                let sink = JsonlSink::create(
+                    Path::new(&output_dir),
+                    options.granularity,
+                    options.compression,
+                    options.shard_size,
+                )?;
+                (Box::new(sink), HashSet::new())
+            }
+            OutputFormat::Wikiextractor => {
+                let sink = WikiExtractorSink::create(
+                    Path::new(&output_dir),
+                    options.granularity,
+                    options.compression,
+                    options.shard_size,
+                    &options.url_base,
+                )?;
+                (Box::new(sink), HashSet::new())
+            }
+        };
+        if resume {
+            println!(
+                "{variant}: resuming after {} committed pages",
+                committed.len()
+            );
+        }
+
+        // Failures from an interrupted run are kept since those pages are tried again
+        let failures = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(resume)
+                .truncate(!resume)
+                .open(&failures_filename)?,
+        );
+
+        // Workers wait once a whole batch is queued up behind the part being written
+        let batch_size = options.batch_size;
+        let (pages, rx) = bounded(batch_size);
+        let writer = thread::spawn(move || write_parts(sink, rx, batch_size));
+
+        Ok(VariantOutput {
+            variant,
+            pages,
+            writer,
+            reorder: (options.order == OutputOrder::Dump).then(Mutex::default),
+            released: Condvar::new(),
+            failures: Mutex::new(failures),
+            failures_error: Mutex::new(None),
+            failures_filename,
+            num_failures: Mutex::new(0),
+            committed,
+        })
+    }
+
+    /// Send a page to the writer thread, which fails once it stopped on an error
+    fn push(&self, page: Page) -> Result<(), SendError<()>> {
+        self.pages.send(page).map_err(|_| SendError(()))
+    }
+
+    /// Hand over the result of the `seq`-th page sent to the workers, which is `None` if it
+    /// failed, was filtered out or is already committed
+    fn complete(&self, seq: u64, page: Option<Page>) -> Result<(), SendError<()>> {
+        match &self.reorder {
+            // Keep the lock while pushing so released pages are not overtaken
+            Some(reorder) => {
+                let mut reorder = reorder.lock().unwrap();
+                let ready = reorder.insert(seq, page);
+                self.released.notify_all();
+                ready.into_iter().try_for_each(|page| self.push(page))
+            }
+            None => page.map_or(Ok(()), |page| self.push(page)),
+        }
+    }
+
+    /// Wait until the `seq`-th page is within `MAX_REORDER_WINDOW` pages of the oldest page not
+    /// written yet, when writing in dump order, or until `stop` is set
+    fn wait_for_window(&self, seq: u64, stop: &AtomicBool) {
+        if let Some(reorder) = &self.reorder {
+            // A worker that panicked while holding the lock left the buffer as it was
+            let reorder = reorder.lock().unwrap_or_else(PoisonError::into_inner);
+            let _reorder = self
+                .released
+                .wait_while(reorder, |reorder| {
+                    !stop.load(Ordering::SeqCst)
+                        && seq.saturating_sub(reorder.next) >= MAX_REORDER_WINDOW
+                })
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Wake up the reader if it waits in `wait_for_window`, after `stop` was set
+    fn wake(&self) {
+        if let Some(reorder) = &self.reorder {
+            let _reorder = reorder.lock().unwrap_or_else(PoisonError::into_inner);
+            self.released.notify_all();
+        }
+    }
+
+    /// Write a page that failed to parse to the failures file. An error writing it is kept for
+    /// `finish` rather than stopping the worker.
+    fn record_failure(&self, page: &Page, error: &ParseError) {
+        let failure = Failure {
+            page_id: page.page_id,
+            title: &page.title,
+            kind: error.kind(),
+            error: error.to_string(),
+        };
+        *self.num_failures.lock().unwrap() += 1;
+        let mut failures = self.failures.lock().unwrap();
+        let written = serde_json::to_writer(&mut *failures, &failure)
+            .map_err(std::io::Error::from)
+            .and_then(|_| failures.write_all(b"\n"));
+        if let Err(e) = written {
+            self.failures_error.lock().unwrap().get_or_insert(e);
+        }
+    }
+
+    /// Wait for the writer thread to write the remaining pages and flush the failures file
+    fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        drop(self.pages);
+        self.writer.join().unwrap()?;
+        if let Some(e) = self.failures_error.into_inner().unwrap() {
+            return Err(e.into());
+        }
+        self.failures.into_inner().unwrap().flush()?;
+        Ok(())
+    }
+}
+
+/// Body of a writer thread, writing a batch every `batch_size` pages received until the channel is closed
+fn write_parts(
+    mut writer: Box<dyn Sink>,
+    pages: Receiver<Page>,
+    batch_size: usize,
+) -> Result<(), String> {
+    let mut batch = Vec::with_capacity(batch_size);
+    for page in pages {
+        batch.push(page);
+        if batch.len() >= batch_size {
+            writer.write(&batch).map_err(|e| e.to_string())?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        writer.write(&batch).map_err(|e| e.to_string())?;
+    }
+    writer.finish().map_err(|e| e.to_string())
+}
+
 fn parse_articles(
     xml_filename: &str,
-    variant: ZhVariant,
-    filter: bool,
+    backend: Arc<dyn Backend>,
+    options: &ParseOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Set up Parquet writer
-    let output_name = format!("wikipedia-{}.parquet", variant);
-    let parquet_path = Path::new(output_name.as_str());
-
-    let message_type = "
-        message schema {
-            REQUIRED INT64 id;
-            REQUIRED INT64 revision_id;
-            REQUIRED INT64 timestamp (TIMESTAMP_MILLIS);
-            REQUIRED BINARY title (UTF8);
-            REQUIRED BINARY content (UTF8);
-        }
-    ";
-    let schema = Arc::new(parse_message_type(message_type)?);
-
-    // Writer properties to enable compression
-    let props = WriterProperties::builder()
-        // Change the compression type if needed, SNAPPY is a default good choice for a balance between size and speed
-        .set_compression(parquet::basic::Compression::SNAPPY)
-        .build();
-
-    let file = fs::File::create(&parquet_path)?;
-    let writer = Arc::new(Mutex::new(SerializedFileWriter::new(
-        file,
-        schema,
-        Arc::new(props),
-    )?));
-
-    // Initialize batch vectors
-    let pages = Arc::new(Mutex::new(vec![]));
-    let batch_size = 1000;
+    let ParseOptions {
+        variants,
+        extract,
+        num_workers,
+        queue_size,
+        ..
+    } = options.clone();
+
+    let outputs = Arc::new(
+        variants
+            .iter()
+            .map(|&variant| VariantOutput::open(variant, options))
+            .collect::<Result<Vec<_>, _>>()?,
+    );
+    // Pages committed in every variant are skipped entirely
+    let is_committed = |page_id: &i64| {
+        outputs
+            .iter()
+            .all(|output| output.committed.contains(page_id))
+    };
+    let num_committed = outputs[0]
+        .committed
+        .iter()
+        .filter(|page_id| is_committed(page_id))
+        .count();
 
     let num_pages = count_pages(xml_filename, true)?;
     // Initialize progress bar
-    let progress_bar = Arc::new(Mutex::new(tqdm!(total = num_pages)));
+    let progress_bar = Arc::new(Mutex::new(tqdm!(
+        total = num_pages,
+        initial = num_committed
+    )));
 
     // Read XML file
     let file = File::open(xml_filename).unwrap();
@@ -571,7 +1525,7 @@ fn parse_articles(
     let mut current_timestamp: Option<i64> = None;
     let mut current_title: Option<String> = None;
 
-    let mut variants: HashMap<String, usize> = HashMap::from_iter(vec![
+    let mut variant_counts: HashMap<String, usize> = HashMap::from_iter(vec![
         ("zh-hans".to_string(), 0),
         ("zh-hant".to_string(), 0),
         ("zh-cn".to_string(), 0),
@@ -582,69 +1536,45 @@ fn parse_articles(
         ("zh-tw".to_string(), 0),
     ]);
 
-    // Spawn worker threads
+    // Spawn worker threads that all take pages from one bounded queue,
+    // so an idle worker picks up the next page and a slow page only holds up its own worker
+    // Pages are numbered in the order they are read to restore that order when writing
+    let (tx, rx) = bounded::<(u64, Page)>(queue_size);
+    // Set once a writer thread stopped on an error, which `finish` reports
+    let stop = Arc::new(AtomicBool::new(false));
     let mut handles = vec![];
-    let mut txs = vec![];
-    for _ in 0..20 {
-        let (tx, rx) = mpsc::channel::<Page>();
-        txs.push(tx);
-        let pages = pages.clone();
-        let writer = writer.clone();
+    for _ in 0..num_workers {
+        let rx = rx.clone();
+        let stop = stop.clone();
+        let outputs = outputs.clone();
         let progress_bar = progress_bar.clone();
+        let backend = backend.clone();
+        let extract = extract.clone();
         let handle = thread::spawn(move || {
-            loop {
-                match rx.recv() {
-                    Ok(Page {
-                        page_id: pageid,
-                        revision_id: revisionid,
-                        timestamp,
-                        title,
-                        content: text,
-                    }) => {
-                        let html_title = request_parse(&title, variant);
-                        if let Some(html_title) = html_title {
-                            let title = html_to_text(&html_title, false);
-                            if !title.is_empty() {
-                                let html_text = request_parse(&text, variant);
-                                if let Some(html_text) = html_text {
-                                    println!("{html_text}");
-                                    let cleaned_text = html_to_text(&html_text, filter);
-                                    if !cleaned_text.is_empty() {
-                                        // Add to batch vectors
-                                        let mut pages = pages.lock().unwrap();
-                                        pages.push(Page {
-                                            page_id: pageid,
-                                            revision_id: revisionid,
-                                            timestamp,
-                                            title,
-                                            content: cleaned_text,
-                                        });
-                                        progress_bar.lock().unwrap().update(1).unwrap();
-
-                                        // Write batch if it reaches the batch size
-                                        if pages.len() >= batch_size {
-                                            let mut writer = writer.lock().unwrap();
-                                            write_batch(&mut writer, &pages).unwrap();
-                                            pages.clear();
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        // Channel has been closed
-                        break;
-                    }
+            let _guard = StopOnPanic {
+                stop: &stop,
+                outputs: &outputs,
+            };
+            // Loop until the channel has been closed or a writer stopped
+            while let Ok((seq, page)) = rx.recv() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
                 }
+                process_page(seq, &page, &outputs, backend.as_ref(), &extract, &stop);
+                if stop.load(Ordering::SeqCst) {
+                    outputs.iter().for_each(VariantOutput::wake);
+                    break;
+                }
+                progress_bar.lock().unwrap().update(1).unwrap();
             }
         });
         handles.push(handle);
     }
+    // Sending fails once all workers stopped
+    drop(rx);
 
-    let mut current_worker = 0;
-
-    loop {
+    let mut seq = 0;
+    'read: loop {
         match reader.read_event_into(&mut buf) {
             Ok(Event::Start(ref e)) => {
                 match e.name().as_ref() {
@@ -661,12 +1591,9 @@ fn parse_articles(
                     _ => {}
                 }
             }
-            Ok(Event::Empty(ref e)) => match e.name().as_ref() {
-                b"redirect" => {
-                    is_article = false;
-                }
-                _ => {}
-            },
+            Ok(Event::Empty(ref e)) if e.name().as_ref() == b"redirect" => {
+                is_article = false;
+            }
             Ok(Event::End(ref e)) => match e.name().as_ref() {
                 b"page" => {
                     inside_page = false;
@@ -694,52 +1621,61 @@ fn parse_articles(
                 }
                 _ => {}
             },
-            Ok(Event::Text(e)) => {
-                if inside_page {
-                    if inside_ns {
-                        let ns = e.unescape().unwrap();
-                        if ns != "0" {
-                            is_article = false; // It's not a main content page
-                        }
-                    } else if inside_id {
-                        if let Ok(id) = e.unescape().unwrap().parse::<i64>() {
-                            if inside_revision {
-                                current_revisionid = Some(id);
-                            } else {
-                                current_pageid = Some(id);
-                            }
+            Ok(Event::Text(e)) if inside_page => {
+                if inside_ns {
+                    let ns = e.unescape().unwrap();
+                    if ns != "0" {
+                        is_article = false; // It's not a main content page
+                    }
+                } else if inside_id {
+                    if let Ok(id) = e.unescape().unwrap().parse::<i64>() {
+                        if inside_revision {
+                            current_revisionid = Some(id);
+                        } else {
+                            current_pageid = Some(id);
                         }
-                    } else if inside_revision && inside_timestamp {
-                        let timestamp_str = e.unescape().unwrap();
-                        let timestamp = chrono::NaiveDateTime::parse_from_str(&timestamp_str, "%+")
-                            .map_err(|e| format!("Failed to parse timestamp: {}", e))?;
-                        let timestamp_millis = timestamp.timestamp_millis();
-                        current_timestamp = Some(timestamp_millis);
-                    } else if inside_title {
-                        current_title = Some(e.unescape().unwrap().to_string());
-                    } else if is_article && inside_text {
-                        let text = e.unescape().unwrap();
-                        for (variant, count) in &mut variants {
-                            if text.contains(&format!("{variant}:")) {
-                                *count += 1;
-                            }
+                    }
+                } else if inside_revision && inside_timestamp {
+                    let timestamp_str = e.unescape().unwrap();
+                    let timestamp = chrono::NaiveDateTime::parse_from_str(&timestamp_str, "%+")
+                        .map_err(|e| format!("Failed to parse timestamp: {}", e))?;
+                    let timestamp_millis = timestamp.timestamp_millis();
+                    current_timestamp = Some(timestamp_millis);
+                } else if inside_title {
+                    current_title = Some(e.unescape().unwrap().to_string());
+                } else if is_article && inside_text {
+                    let text = e.unescape().unwrap();
+                    for (variant, count) in &mut variant_counts {
+                        if text.contains(&format!("{variant}:")) {
+                            *count += 1;
                         }
-                        if let Some(pageid) = current_pageid {
-                            if let Some(revisionid) = current_revisionid {
-                                if let Some(timestamp) = current_timestamp.as_ref() {
-                                    if let Some(title) = current_title.as_ref() {
-                                        txs[current_worker]
-                                            .send(Page {
-                                                page_id: pageid,
-                                                revision_id: revisionid,
-                                                timestamp: *timestamp,
-                                                title: title.to_string(),
-                                                content: text.to_string(),
-                                            })
-                                            .unwrap();
-                                        current_worker = (current_worker + 1) % 20;
-                                        // Rotate workers
+                    }
+                    if let Some(pageid) = current_pageid.filter(|id| !is_committed(id)) {
+                        if let Some(revisionid) = current_revisionid {
+                            if let Some(timestamp) = current_timestamp.as_ref() {
+                                if let Some(title) = current_title.as_ref() {
+                                    for output in outputs.iter() {
+                                        output.wait_for_window(seq, &stop);
+                                    }
+                                    if stop.load(Ordering::SeqCst) {
+                                        break 'read;
+                                    }
+                                    // Blocks while the queue is full
+                                    let sent = tx.send((
+                                        seq,
+                                        Page {
+                                            page_id: pageid,
+                                            revision_id: revisionid,
+                                            timestamp: *timestamp,
+                                            title: title.to_string(),
+                                            content: text.to_string(),
+                                            ..Page::default()
+                                        },
+                                    ));
+                                    if sent.is_err() {
+                                        break 'read;
                                     }
+                                    seq += 1;
                                 }
                             }
                         }
@@ -756,165 +1692,493 @@ fn parse_articles(
         buf.clear();
     }
 
-    // Close all channels to stop the worker threads
-    for tx in txs {
-        drop(tx);
-    }
+    // Close the queue to stop the worker threads
+    drop(tx);
 
     // Wait for all worker threads to complete
-    for handle in handles {
-        handle.join().unwrap();
-    }
-
-    // Write any remaining items in the batch vectors
-    let pages = pages.lock().unwrap();
-    if !pages.is_empty() {
-        write_batch(&mut writer.lock().unwrap(), &pages)?;
-    }
-
-    Arc::try_unwrap(writer)
-        .unwrap()
-        .into_inner()
-        .unwrap()
-        .close()?;
+    let num_panicked = handles
+        .into_iter()
+        .map(JoinHandle::join)
+        .filter(Result::is_err)
+        .count();
 
+    // Write the remaining pages once all workers are done with the outputs
+    let outputs = Arc::into_inner(outputs).unwrap();
     println!("Number of articles: {}", article_count);
-    for (variant, count) in variants {
+    for output in outputs {
+        println!(
+            "Number of failed pages in {}: {} (see {})",
+            output.variant,
+            output.num_failures.lock().unwrap(),
+            output.failures_filename
+        );
+        output.finish()?;
+    }
+    for (variant, count) in variant_counts {
         println!("{}: {}", variant, count);
     }
+    if num_panicked > 0 {
+        return Err(format!("{num_panicked} worker threads panicked").into());
+    }
 
     Ok(())
 }
 
-fn write_batch(
-    writer: &mut SerializedFileWriter<File>,
-    pages: &[Page],
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut row_group_writer = writer.next_row_group()?;
-
-    // Write ID column
-    if let Some(mut col_writer) = row_group_writer.next_column()? {
-        if let ColumnWriter::Int64ColumnWriter(ref mut writer) = col_writer.untyped() {
-            writer.write_batch(
-                &pages
-                    .iter()
-                    .map(|Page { page_id, .. }| *page_id)
-                    .collect::<Vec<_>>(),
-                None,
-                None,
-            )?;
+/// Parse `page` into every output it is not committed in yet and hand the results over. A page
+/// whose parsing panics is recorded as failed, so it is still completed and the reader does not
+/// wait for it forever when writing in dump order.
+fn process_page(
+    seq: u64,
+    page: &Page,
+    outputs: &[VariantOutput],
+    backend: &dyn Backend,
+    extract: &ExtractOptions,
+    stop: &AtomicBool,
+) {
+    let (pending, committed): (Vec<&VariantOutput>, Vec<&VariantOutput>) = outputs
+        .iter()
+        .partition(|output| !output.committed.contains(&page.page_id));
+    let variants: Vec<ZhVariant> = pending.iter().map(|output| output.variant).collect();
+    let results = panic::catch_unwind(AssertUnwindSafe(|| {
+        parse_page(backend, page, &variants, extract)
+    }))
+    .unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        variants
+            .iter()
+            .map(|_| Err(ParseError::Panic(message.clone())))
+            .collect()
+    });
+    for (output, result) in pending.into_iter().zip(results) {
+        let parsed = match result {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                output.record_failure(page, &e);
+                None
+            }
+        };
+        if output.complete(seq, parsed).is_err() {
+            stop.store(true, Ordering::SeqCst);
         }
-        col_writer.close()?;
-    }
-
-    // Write Revision ID column
-    if let Some(mut col_writer) = row_group_writer.next_column()? {
-        if let ColumnWriter::Int64ColumnWriter(ref mut writer) = col_writer.untyped() {
-            writer.write_batch(
-                &pages
-                    .iter()
-                    .map(|Page { revision_id, .. }| *revision_id)
-                    .collect::<Vec<_>>(),
-                None,
-                None,
-            )?;
+    }
+    for output in committed {
+        if output.complete(seq, None).is_err() {
+            stop.store(true, Ordering::SeqCst);
         }
-        col_writer.close()?;
-    }
-
-    // Write Timestamp column
-    if let Some(mut col_writer) = row_group_writer.next_column()? {
-        if let ColumnWriter::Int64ColumnWriter(ref mut writer) = col_writer.untyped() {
-            writer.write_batch(
-                &pages
-                    .iter()
-                    .map(|Page { timestamp, .. }| *timestamp)
-                    .collect::<Vec<_>>(),
-                None,
-                None,
-            )?;
+    }
+}
+
+/// Stops the run when a worker thread panics outside of `parse_page`, waking up the reader so it
+/// does not wait for pages that will never be completed
+struct StopOnPanic<'a> {
+    stop: &'a AtomicBool,
+    outputs: &'a [VariantOutput],
+}
+
+impl Drop for StopOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.stop.store(true, Ordering::SeqCst);
+            self.outputs.iter().for_each(VariantOutput::wake);
         }
-        col_writer.close()?;
-    }
-
-    // Write Title column
-    if let Some(mut col_writer) = row_group_writer.next_column()? {
-        if let ColumnWriter::ByteArrayColumnWriter(ref mut writer) = col_writer.untyped() {
-            writer.write_batch(
-                &pages
-                    .iter()
-                    .map(|Page { title, .. }| ByteArray::from(title.as_str()))
-                    .collect::<Vec<_>>(),
-                None,
-                None,
-            )?;
+    }
+}
+
+/// Commit state of a `parse_articles` run, stored as `checkpoint.json` next to the part files
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    /// Parts `part-00000.parquet` up to but excluding `part-{num_parts}.parquet` are committed
+    num_parts: usize,
+    num_pages: usize,
+    /// Largest page ID in the most recently committed part
+    last_page_id: Option<i64>,
+}
+
+/// Writes every batch of pages into its own Parquet part file and commits it to the checkpoint
+/// once the file is complete, so an interrupted run never leaves a truncated file behind.
+struct PartWriter {
+    dir: PathBuf,
+    granularity: Granularity,
+    props: WriterProperties,
+    checkpoint: Checkpoint,
+}
+
+impl PartWriter {
+    /// Open `dir` for writing part files, together with the IDs of the pages it already contains.
+    /// Without `resume`, existing part files are an error rather than being overwritten.
+    fn open(
+        dir: &Path,
+        granularity: Granularity,
+        resume: bool,
+    ) -> Result<(PartWriter, HashSet<i64>), Box<dyn std::error::Error>> {
+        fs::create_dir_all(dir)?;
+        let checkpoint_path = dir.join("checkpoint.json");
+
+        let checkpoint = if checkpoint_path.exists() {
+            if !resume {
+                return Err(format!(
+                    "{} already contains a run, pass --resume to continue it or remove it",
+                    dir.display()
+                )
+                .into());
+            }
+            serde_json::from_reader(BufReader::new(File::open(&checkpoint_path)?))?
+        } else {
+            Checkpoint::default()
+        };
+
+        // Remove parts and temporary files that were never committed
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let uncommitted = match part_number(&path) {
+                Some(n) => n >= checkpoint.num_parts,
+                None => path.extension().is_some_and(|ext| ext == "tmp"),
+            };
+            if uncommitted {
+                fs::remove_file(&path)?;
+            }
         }
-        col_writer.close()?;
-    }
-
-    // Write Content column
-    if let Some(mut col_writer) = row_group_writer.next_column()? {
-        if let ColumnWriter::ByteArrayColumnWriter(ref mut writer) = col_writer.untyped() {
-            writer.write_batch(
-                &pages
-                    .iter()
-                    .map(|Page { content, .. }| ByteArray::from(content.as_str()))
-                    .collect::<Vec<_>>(),
-                None,
-                None,
-            )?;
+
+        let mut committed = HashSet::new();
+        for n in 0..checkpoint.num_parts {
+            committed.extend(read_page_ids(&part_path(dir, n))?);
         }
-        col_writer.close()?;
+
+        let props = WriterProperties::builder()
+            // Change the compression type if needed, SNAPPY is a default good choice for a balance between size and speed
+            .set_compression(parquet::basic::Compression::SNAPPY)
+            .build();
+
+        let writer = PartWriter {
+            dir: dir.to_path_buf(),
+            granularity,
+            props,
+            checkpoint,
+        };
+        Ok((writer, committed))
     }
 
-    row_group_writer.close()?;
-    Ok(())
+    /// Write `pages` as the next part file and commit it
+    fn write(&mut self, pages: &[Page]) -> Result<(), Box<dyn std::error::Error>> {
+        let path = part_path(&self.dir, self.checkpoint.num_parts);
+        let tmp_path = path.with_extension("parquet.tmp");
+
+        let batch = match self.granularity {
+            Granularity::Paragraph => record::to_record_batch(pages)?,
+            Granularity::Sentence => record::sentences_to_record_batch(
+                &pages.iter().flat_map(sentence::sentences).collect_vec(),
+            )?,
+        };
+        let file = File::create(&tmp_path)?;
+        let mut writer = ArrowWriter::try_new(file, batch.schema(), Some(self.props.clone()))?;
+        writer.write(&batch)?;
+        writer.close()?;
+        fs::rename(&tmp_path, &path)?;
+
+        self.checkpoint.num_parts += 1;
+        self.checkpoint.num_pages += pages.len();
+        self.checkpoint.last_page_id = pages.iter().map(|page| page.page_id).max();
+
+        // Replace the checkpoint atomically so it is never half written
+        let checkpoint_path = self.dir.join("checkpoint.json");
+        let tmp_path = checkpoint_path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&self.checkpoint)?)?;
+        fs::rename(&tmp_path, &checkpoint_path)?;
+        Ok(())
+    }
 }
 
-fn read_from_parquet(
-    input_name: &str,
-    ids: Option<HashSet<i64>>,
-) -> Result<Vec<Page>, Box<dyn std::error::Error>> {
-    use parquet::file::reader::FileReader;
-    use parquet::file::serialized_reader::SerializedFileReader;
-
-    // Define the path for the Parquet file.
-    let parquet_path = Path::new(input_name);
-
-    // Open the Parquet file.
-    let file = File::open(&parquet_path)?;
-    let reader = SerializedFileReader::new(file)?;
-
-    // Get the Parquet file metadata.
-    let metadata = reader.metadata();
-    let schema = metadata.file_metadata().schema();
-
-    // Create an iterator to read row groups.
-    let iter = reader.get_row_iter(Some(schema.clone()))?;
-
-    let mut pages = vec![];
-
-    // Print the first 10 rows.
-    for row in iter {
-        let row = row?;
-        let page_id: i64 = row.get_long(0).unwrap();
-        if match ids {
-            Some(ref ids) => ids.contains(&page_id),
-            None => true,
-        } {
-            let revision_id: i64 = row.get_long(1).unwrap();
-            let timestamp_millis: i64 = row.get_timestamp_millis(2).unwrap();
-            let title: &str = row.get_string(3).unwrap();
-            let content: &str = row.get_string(4).unwrap();
-            pages.push(Page {
-                page_id,
-                revision_id,
-                timestamp: timestamp_millis,
-                title: title.to_string(),
-                content: content.to_string(),
-            });
+fn part_path(dir: &Path, n: usize) -> PathBuf {
+    dir.join(format!("part-{n:05}.parquet"))
+}
+
+/// Number `n` of a `part-{n}.parquet` file
+fn part_number(path: &Path) -> Option<usize> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("part-")?
+        .strip_suffix(".parquet")?
+        .parse()
+        .ok()
+}
+
+/// A single Parquet file, or all part files in a directory in order
+fn parquet_files(input_name: &str) -> std::io::Result<Vec<PathBuf>> {
+    let path = Path::new(input_name);
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut parts = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .filter_ok(|path| part_number(path).is_some())
+        .collect::<std::io::Result<Vec<_>>>()?;
+    parts.sort_by_key(|path| part_number(path));
+    Ok(parts)
+}
+
+/// Read only the page ID column of a Parquet file
+/// Works for parts of both granularities, which both have an `id` column
+fn read_page_ids(path: &Path) -> Result<HashSet<i64>, Box<dyn std::error::Error>> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+    let index = builder
+        .parquet_schema()
+        .columns()
+        .iter()
+        .position(|column| column.name() == "id")
+        .ok_or_else(|| format!("{}: missing column `id`", path.display()))?;
+    let projection = ProjectionMask::leaves(builder.parquet_schema(), [index]);
+
+    let mut ids = HashSet::new();
+    for batch in builder.with_projection(projection).build()? {
+        let batch = batch?;
+        let column = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .ok_or_else(|| format!("{}: column `id` is not INT64", path.display()))?;
+        ids.extend(column.values().iter().copied());
+    }
+    Ok(ids)
+}
+
+/// Parser of counts like the number of workers, which must not be 0
+fn at_least_one() -> RangedU64ValueParser<usize> {
+    RangedU64ValueParser::new().range(1..)
+}
+
+/// Milliseconds since the epoch of a `2023-01-01` date or an RFC 3339 time
+fn parse_timestamp(s: &str) -> Result<i64, String> {
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().timestamp_millis());
+    }
+    chrono::DateTime::parse_from_rfc3339(s)
+        .map(|time| time.timestamp_millis())
+        .map_err(|e| format!("expected a date like 2023-01-01 or an RFC 3339 time: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(page_id: i64) -> Page {
+        Page {
+            page_id,
+            ..Page::default()
+        }
+    }
+
+    fn ids(pages: Vec<Page>) -> Vec<i64> {
+        pages.into_iter().map(|page| page.page_id).collect()
+    }
+
+    #[test]
+    fn reorder_buffer_releases_pages_in_order() {
+        let mut reorder = ReorderBuffer::default();
+        assert_eq!(ids(reorder.insert(2, Some(page(2)))), Vec::<i64>::new());
+        assert_eq!(ids(reorder.insert(1, None)), Vec::<i64>::new());
+        assert_eq!(ids(reorder.insert(0, Some(page(0)))), vec![0, 2]);
+        assert_eq!(reorder.next, 3);
+        assert!(reorder.waiting.is_empty());
+        assert_eq!(ids(reorder.insert(3, Some(page(3)))), vec![3]);
+    }
+
+    fn section(heading: &str, level: u8, paragraphs: &[&str]) -> Section {
+        Section {
+            heading: heading.to_string(),
+            level,
+            paragraphs: paragraphs.iter().map(|p| p.to_string()).collect(),
         }
     }
 
-    Ok(pages)
+    #[test]
+    fn sections_follow_headings_and_abstract_is_the_lead() {
+        let (blocks, _) = html_to_blocks(
+            concat!(
+                "<p>北京是中国的<b>首都</b>。\n</p><p>人口众多。\n</p>",
+                "<h2><span class=\"mw-headline\">历史</span></h2><p>第一段。\n</p>",
+                "<h3>古代<i>时期</i></h3><p>第二段。\n</p><h2>参考文献</h2>",
+            ),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            blocks_to_sections(&blocks, false),
+            vec![
+                section("", 0, &["北京是中国的首都。", "人口众多。"]),
+                section("历史", 2, &["第一段。"]),
+                section("古代时期", 3, &["第二段。"]),
+                section("参考文献", 2, &[]),
+            ]
+        );
+        assert_eq!(
+            blocks_to_abstract(&blocks, false),
+            "北京是中国的首都。\n人口众多。"
+        );
+
+        // Without a lead there is no lead section and no abstract
+        let (blocks, _) = html_to_blocks("<h2>历史</h2><p>第一段。\n</p>", &[]).unwrap();
+        assert_eq!(
+            blocks_to_sections(&blocks, false),
+            vec![section("历史", 2, &["第一段。"])]
+        );
+        assert_eq!(blocks_to_abstract(&blocks, false), "");
+    }
+
+    fn element(kind: ElementKind, heading: &str, text: &str) -> Element {
+        Element {
+            kind,
+            heading: heading.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn elements_keep_their_heading_and_inner_spacing() {
+        let (blocks, _) = html_to_blocks(
+            concat!(
+                "<ul><li>第一项</li><li>第二项 <a class=\"external text\" href=\"https://a.org\">",
+                "外部链接</a></li><li> </li></ul>",
+                "<h2>定义 <i>列表</i></h2><dl><dt>术语</dt><dd><p>一段</p><p>两段</p></dd></dl>",
+                "<blockquote>引文</blockquote>",
+                "<table><tr><td><ul><li>表格内</li></ul></td></tr></table>",
+                "<div class=\"navbox\"><ul><li>导航</li></ul></div>",
+            ),
+            &[ElementKind::Li, ElementKind::Dt, ElementKind::Dd],
+        )
+        .unwrap();
+        assert_eq!(
+            blocks_to_elements(&blocks),
+            vec![
+                element(ElementKind::Li, "", "第一项"),
+                element(ElementKind::Li, "", "第二项 外部链接"),
+                element(ElementKind::Dt, "定义 列表", "术语"),
+                element(ElementKind::Dd, "定义 列表", "一段\n两段"),
+            ]
+        );
+    }
+
+    #[test]
+    fn part_writer_resumes_after_committed_parts() {
+        let dir = std::env::temp_dir().join(format!("part-writer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (mut writer, committed) =
+            PartWriter::open(&dir, Granularity::Paragraph, false).unwrap();
+        assert!(committed.is_empty());
+        writer.write(&[page(1), page(2)]).unwrap();
+        writer.write(&[page(3)]).unwrap();
+        // A run interrupted while writing the third part, and one interrupted before committing it
+        fs::copy(part_path(&dir, 0), part_path(&dir, 2)).unwrap();
+        fs::write(dir.join("part-00003.parquet.tmp"), "truncated").unwrap();
+        drop(writer);
+
+        assert!(PartWriter::open(&dir, Granularity::Paragraph, false).is_err());
+        let (mut writer, committed) = PartWriter::open(&dir, Granularity::Paragraph, true).unwrap();
+        assert_eq!(committed, HashSet::from([1, 2, 3]));
+        assert!(!part_path(&dir, 2).exists());
+        assert!(!dir.join("part-00003.parquet.tmp").exists());
+
+        writer.write(&[page(5), page(4)]).unwrap();
+        assert_eq!(
+            read_page_ids(&part_path(&dir, 2)).unwrap(),
+            HashSet::from([4, 5])
+        );
+        let checkpoint: Checkpoint =
+            serde_json::from_slice(&fs::read(dir.join("checkpoint.json")).unwrap()).unwrap();
+        assert_eq!(checkpoint.num_parts, 3);
+        assert_eq!(checkpoint.num_pages, 5);
+        assert_eq!(checkpoint.last_page_id, Some(5));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Renders nothing but panics
+    struct PanickingBackend;
+
+    impl Backend for PanickingBackend {
+        fn render(&self, _wikitext: &str, _variant: ZhVariant) -> Result<String, ParseError> {
+            panic!("cannot render");
+        }
+    }
+
+    /// An output written in dump order whose writer thread drops every page, with its failures
+    /// file in `dir`
+    fn dump_order_output(dir: &Path) -> VariantOutput {
+        fs::create_dir_all(dir).unwrap();
+        let failures_filename = dir.join("failures.jsonl").to_str().unwrap().to_string();
+        let (pages, rx) = bounded(1);
+        VariantOutput {
+            variant: ZhVariant::Zh,
+            pages,
+            writer: thread::spawn(move || {
+                rx.into_iter().for_each(drop);
+                Ok(())
+            }),
+            reorder: Some(Mutex::default()),
+            released: Condvar::new(),
+            failures: Mutex::new(BufWriter::new(File::create(&failures_filename).unwrap())),
+            failures_error: Mutex::new(None),
+            failures_filename,
+            num_failures: Mutex::new(0),
+            committed: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn panicking_page_is_recorded_and_completed() {
+        let dir = std::env::temp_dir().join(format!("panicking-page-{}", std::process::id()));
+        let outputs = vec![dump_order_output(&dir)];
+        let stop = AtomicBool::new(false);
+        let page = Page {
+            page_id: 7,
+            title: "标题".to_string(),
+            ..Page::default()
+        };
+        process_page(
+            0,
+            &page,
+            &outputs,
+            &PanickingBackend,
+            &ExtractOptions::default(),
+            &stop,
+        );
+        assert!(!stop.load(Ordering::SeqCst));
+        // Returns at once since the page no longer holds up the window
+        outputs[0].wait_for_window(MAX_REORDER_WINDOW, &stop);
+
+        let output = outputs.into_iter().next().unwrap();
+        assert_eq!(*output.num_failures.lock().unwrap(), 1);
+        let failures_filename = output.failures_filename.clone();
+        output.finish().unwrap();
+        let failures = fs::read_to_string(failures_filename).unwrap();
+        assert!(failures.contains(r#""page_id":7"#));
+        assert!(failures.contains(r#""kind":"panic""#));
+        assert!(failures.contains("cannot render"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn panicking_worker_stops_the_reader() {
+        let dir = std::env::temp_dir().join(format!("panicking-worker-{}", std::process::id()));
+        let outputs = Arc::new(vec![dump_order_output(&dir)]);
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let outputs = outputs.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let _guard = StopOnPanic {
+                    stop: &stop,
+                    outputs: &outputs,
+                };
+                panic!("page 0 is never completed");
+            })
+        };
+        // Would wait forever for page 0 if the panic did not stop the run
+        outputs[0].wait_for_window(MAX_REORDER_WINDOW, &stop);
+        assert!(worker.join().is_err());
+        assert!(stop.load(Ordering::SeqCst));
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
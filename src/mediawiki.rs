@@ -0,0 +1,306 @@
+use serde::Deserialize;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+use ureq::{Agent, AgentBuilder};
+
+use crate::ZhVariant;
+
+#[derive(Debug, Deserialize)]
+struct Parse {
+    text: Text,
+}
+
+#[derive(Debug, Deserialize)]
+struct Text {
+    #[serde(rename = "*")]
+    content: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    code: String,
+    #[serde(default)]
+    info: String,
+}
+
+/// Either `parse` or `error` is present depending on whether MediaWiki could render the text
+#[derive(Debug, Deserialize)]
+struct ParseResponse {
+    parse: Option<Parse>,
+    error: Option<ApiError>,
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// The request could not be sent or the response could not be read
+    Transport(String),
+    /// MediaWiki answered with a non-success HTTP status
+    Status(u16),
+    /// MediaWiki answered with an `error` object, e.g. `toobig` or `internal_api_error_*`
+    Api { code: String, info: String },
+    /// The response body is not a valid `action=parse` JSON response
+    Json(String),
+    /// The rendered HTML could not be read
+    Html(String),
+    /// Extracting the text panicked
+    Panic(String),
+}
+
+impl ParseError {
+    /// Short name of the error variant, used in failure records
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ParseError::Transport(_) => "transport",
+            ParseError::Status(_) => "status",
+            ParseError::Api { .. } => "api",
+            ParseError::Json(_) => "json",
+            ParseError::Html(_) => "html",
+            ParseError::Panic(_) => "panic",
+        }
+    }
+
+    /// Whether sending the same request again might succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ParseError::Transport(_) => true,
+            ParseError::Status(code) => *code == 429 || *code >= 500,
+            ParseError::Api { code, .. } => {
+                code.starts_with("internal_api_error")
+                    || code == "ratelimited"
+                    || code == "readonly"
+            }
+            // Usually a PHP fatal error or a truncated body, which is worth another try
+            ParseError::Json(_) => true,
+            ParseError::Html(_) | ParseError::Panic(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::Transport(e) => write!(f, "transport error: {e}"),
+            ParseError::Status(code) => write!(f, "HTTP status {code}"),
+            ParseError::Api { code, info } => write!(f, "MediaWiki error {code}: {info}"),
+            ParseError::Json(e) => write!(f, "failed to parse response JSON: {e}"),
+            ParseError::Html(e) => write!(f, "failed to read rendered HTML: {e}"),
+            ParseError::Panic(e) => write!(f, "panicked: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// How often and how long to wait before resending a request that failed with a retryable error.
+/// The delay doubles after every attempt, up to `max_backoff`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+/// Client for the `action=parse` endpoint of a MediaWiki API.
+/// Cloning is cheap and all clones share the same connection pool.
+#[derive(Clone)]
+pub struct MediaWikiClient {
+    agent: Agent,
+    api_url: String,
+    retry: RetryPolicy,
+}
+
+impl MediaWikiClient {
+    pub fn new(
+        api_url: &str,
+        timeout: Duration,
+        max_connections: usize,
+        retry: RetryPolicy,
+    ) -> Self {
+        let agent = AgentBuilder::new()
+            .timeout(timeout)
+            .max_idle_connections(max_connections)
+            .max_idle_connections_per_host(max_connections)
+            .build();
+        MediaWikiClient {
+            agent,
+            api_url: api_url.to_string(),
+            retry,
+        }
+    }
+
+    /// Render `text` as wikitext into HTML in the given variant, retrying according to the
+    /// client's `RetryPolicy`. Returns the last error once all retries are used up.
+    pub fn parse(&self, text: &str, variant: ZhVariant) -> Result<String, ParseError> {
+        let mut retry = 0;
+        loop {
+            match self.parse_once(text, variant) {
+                Err(e) if e.is_retryable() && retry < self.retry.max_retries => {
+                    thread::sleep(self.retry.backoff(retry));
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The wikitext is sent as a form-encoded POST body so there is no limit on its length.
+    fn parse_once(&self, text: &str, variant: ZhVariant) -> Result<String, ParseError> {
+        let lang = variant.to_string();
+        let response = self.agent.post(&self.api_url).send_form(&[
+            ("action", "parse"),
+            ("format", "json"),
+            ("contentmodel", "wikitext"),
+            ("uselang", &lang),
+            ("text", text),
+        ]);
+        let response = match response {
+            Ok(response) => response,
+            Err(ureq::Error::Status(code, _)) => return Err(ParseError::Status(code)),
+            Err(ureq::Error::Transport(e)) => return Err(ParseError::Transport(e.to_string())),
+        };
+
+        // Read the body ourselves since `into_string` caps responses at 10MB
+        let mut body = String::new();
+        response
+            .into_reader()
+            .read_to_string(&mut body)
+            .map_err(|e| ParseError::Transport(e.to_string()))?;
+
+        let res = serde_json::from_str::<ParseResponse>(&body)
+            .map_err(|e| ParseError::Json(e.to_string()))?;
+        match res {
+            ParseResponse {
+                parse: Some(parse), ..
+            } => Ok(parse.text.content),
+            ParseResponse {
+                error: Some(ApiError { code, info }),
+                ..
+            } => Err(ParseError::Api { code, info }),
+            ParseResponse { .. } => Err(ParseError::Json(
+                "response has neither `parse` nor `error`".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Serve `responses` as `(status, body)`, one per connection, and send back the request
+    /// bodies received
+    fn serve(responses: Vec<(u16, &'static str)>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api.php", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut request = vec![0; length];
+                reader.read_exact(&mut request).unwrap();
+                sender.send(String::from_utf8(request).unwrap()).unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {status} OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn client(url: &str, max_retries: u32) -> MediaWikiClient {
+        let retry = RetryPolicy {
+            max_retries,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        MediaWikiClient::new(url, Duration::from_secs(5), 1, retry)
+    }
+
+    #[test]
+    fn parse_returns_the_html() {
+        let (url, requests) = serve(vec![(200, r#"{"parse":{"text":{"*":"<p>a</p>"}}}"#)]);
+        let html = client(&url, 0).parse("'''a'''", ZhVariant::Hans).unwrap();
+        assert_eq!(html, "<p>a</p>");
+        let request = requests.recv().unwrap();
+        assert!(request.contains("action=parse"));
+        assert!(request.contains("uselang=zh-hans"));
+        assert!(request.contains("text=%27%27%27a%27%27%27"));
+    }
+
+    #[test]
+    fn api_errors_are_returned() {
+        let (url, _requests) = serve(vec![(
+            200,
+            r#"{"error":{"code":"toobig","info":"too big"}}"#,
+        )]);
+        let error = client(&url, 0).parse_once("a", ZhVariant::Zh).unwrap_err();
+        assert!(matches!(
+            &error,
+            ParseError::Api { code, info } if code == "toobig" && info == "too big"
+        ));
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn server_errors_are_retried() {
+        let (url, requests) = serve(vec![
+            (503, "{}"),
+            (502, "{}"),
+            (200, r#"{"parse":{"text":{"*":"ok"}}}"#),
+        ]);
+        assert_eq!(client(&url, 2).parse("a", ZhVariant::Zh).unwrap(), "ok");
+        assert_eq!(requests.iter().count(), 3);
+
+        let (url, requests) = serve(vec![(500, "{}"), (500, "{}")]);
+        let error = client(&url, 1).parse("a", ZhVariant::Zh).unwrap_err();
+        assert!(matches!(error, ParseError::Status(500)));
+        assert_eq!(requests.iter().count(), 2);
+    }
+
+    #[test]
+    fn malformed_json_is_an_error() {
+        let (url, _requests) = serve(vec![
+            (200, "<br />Fatal error"),
+            (200, r#"{"batchcomplete":""}"#),
+        ]);
+        let client = client(&url, 0);
+        assert!(matches!(
+            client.parse_once("a", ZhVariant::Zh),
+            Err(ParseError::Json(_))
+        ));
+        assert!(matches!(
+            client.parse_once("a", ZhVariant::Zh),
+            Err(ParseError::Json(_))
+        ));
+    }
+}
@@ -0,0 +1,219 @@
+// Streaming reads of page files, which only decode the columns and row groups a query needs
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::arrow::ProjectionMask;
+use parquet::file::metadata::RowGroupMetaData;
+use parquet::file::statistics::Statistics;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
+use std::path::PathBuf;
+
+use crate::{record, Page};
+
+/// Which pages and columns to read. Every filter that is set must match.
+#[derive(Debug, Clone, Default)]
+pub struct PageQuery {
+    /// Columns to decode, all if `None`. Columns used by filters are always decoded.
+    pub columns: Option<Vec<String>>,
+    pub ids: Option<HashSet<i64>>,
+    pub titles: Option<HashSet<String>>,
+    /// Earliest timestamp in milliseconds, inclusive
+    pub since: Option<i64>,
+    /// Latest timestamp in milliseconds, exclusive
+    pub until: Option<i64>,
+}
+
+impl PageQuery {
+    fn matches(&self, page: &Page) -> bool {
+        self.ids
+            .as_ref()
+            .is_none_or(|ids| ids.contains(&page.page_id))
+            && self
+                .titles
+                .as_ref()
+                .is_none_or(|titles| titles.contains(&page.title))
+            && self.since.is_none_or(|since| page.timestamp >= since)
+            && self.until.is_none_or(|until| page.timestamp < until)
+    }
+
+    /// Columns to decode to answer the query, an error if a requested column is not a page column
+    fn projection(&self) -> Result<Option<HashSet<&str>>, Box<dyn Error>> {
+        let Some(requested) = &self.columns else {
+            return Ok(None);
+        };
+        let schema = record::page_schema();
+        if let Some(unknown) = requested
+            .iter()
+            .find(|name| schema.field_with_name(name).is_err())
+        {
+            let known: Vec<&str> = schema
+                .fields()
+                .iter()
+                .map(|field| field.name().as_str())
+                .collect();
+            return Err(format!(
+                "unknown column `{unknown}`, expected some of {}",
+                known.join(", ")
+            )
+            .into());
+        }
+        let mut columns: HashSet<&str> = requested.iter().map(String::as_str).collect();
+        if self.ids.is_some() {
+            columns.insert("id");
+        }
+        if self.titles.is_some() {
+            columns.insert("title");
+        }
+        if self.since.is_some() || self.until.is_some() {
+            columns.insert("timestamp");
+        }
+        Ok(Some(columns))
+    }
+
+    /// Whether the statistics of `row_group` rule out any match
+    fn skips(&self, row_group: &RowGroupMetaData) -> bool {
+        if let Some(ids) = &self.ids {
+            if let Some((min, max)) = int64_range(row_group, "id") {
+                if !ids.iter().any(|id| (min..=max).contains(id)) {
+                    return true;
+                }
+            }
+        }
+        if let Some((min, max)) = int64_range(row_group, "timestamp") {
+            if self.since.is_some_and(|since| max < since)
+                || self.until.is_some_and(|until| min >= until)
+            {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Minimum and maximum of an INT64 column in a row group, if the writer recorded them
+fn int64_range(row_group: &RowGroupMetaData, name: &str) -> Option<(i64, i64)> {
+    let column = row_group
+        .columns()
+        .iter()
+        .find(|column| column.column_path().string() == name)?;
+    match column.statistics()? {
+        Statistics::Int64(statistics) if statistics.has_min_max_set() => {
+            Some((*statistics.min(), *statistics.max()))
+        }
+        _ => None,
+    }
+}
+
+/// Iterator over the pages of several files matching a `PageQuery`, reading one batch at a time
+pub struct PageReader {
+    files: std::vec::IntoIter<PathBuf>,
+    query: PageQuery,
+    batches: Option<ParquetRecordBatchReader>,
+    pages: std::vec::IntoIter<Page>,
+}
+
+impl PageReader {
+    pub fn new(files: Vec<PathBuf>, query: PageQuery) -> PageReader {
+        PageReader {
+            files: files.into_iter(),
+            query,
+            batches: None,
+            pages: vec![].into_iter(),
+        }
+    }
+
+    fn open(&self, path: &PathBuf) -> Result<ParquetRecordBatchReader, Box<dyn Error>> {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+        record::check_schema(builder.schema()).map_err(|e| format!("{}: {e}", path.display()))?;
+
+        let row_groups = builder
+            .metadata()
+            .row_groups()
+            .iter()
+            .enumerate()
+            .filter(|(_, row_group)| !self.query.skips(row_group))
+            .map(|(i, _)| i)
+            .collect();
+        let mut builder = builder.with_row_groups(row_groups);
+
+        if let Some(columns) = self.query.projection()? {
+            let roots: Vec<usize> = builder
+                .schema()
+                .fields()
+                .iter()
+                .enumerate()
+                .filter(|(_, field)| columns.contains(field.name().as_str()))
+                .map(|(i, _)| i)
+                .collect();
+            let mask = ProjectionMask::roots(builder.parquet_schema(), roots);
+            builder = builder.with_projection(mask);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+impl Iterator for PageReader {
+    type Item = Result<Page, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(page) = self.pages.next() {
+                return Some(Ok(page));
+            }
+            if let Some(batches) = &mut self.batches {
+                match batches.next() {
+                    Some(Ok(batch)) => match record::from_record_batch(&batch) {
+                        Ok(pages) => {
+                            let query = &self.query;
+                            self.pages = pages
+                                .into_iter()
+                                .filter(|page| query.matches(page))
+                                .collect::<Vec<_>>()
+                                .into_iter();
+                        }
+                        Err(e) => return Some(Err(e)),
+                    },
+                    Some(Err(e)) => return Some(Err(e.into())),
+                    None => self.batches = None,
+                }
+                continue;
+            }
+            let path = self.files.next()?;
+            match self.open(&path) {
+                Ok(batches) => self.batches = Some(batches),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(columns: &[&str]) -> PageQuery {
+        PageQuery {
+            columns: Some(columns.iter().map(|column| column.to_string()).collect()),
+            ..PageQuery::default()
+        }
+    }
+
+    #[test]
+    fn projection_adds_filter_columns() {
+        assert!(PageQuery::default().projection().unwrap().is_none());
+        let query = PageQuery {
+            ids: Some(HashSet::from([1])),
+            ..query(&["title"])
+        };
+        assert_eq!(
+            query.projection().unwrap(),
+            Some(HashSet::from(["id", "title"]))
+        );
+    }
+
+    #[test]
+    fn projection_rejects_unknown_columns() {
+        let error = query(&["title", "txet"]).projection().unwrap_err();
+        assert!(error.to_string().starts_with("unknown column `txet`"));
+    }
+}
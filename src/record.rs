@@ -0,0 +1,378 @@
+// Conversion between `Page`s and Arrow record batches, which are written to and read from Parquet.
+// Columns are matched by name, so a file whose columns differ from `Page` is an error rather than
+// being read into the wrong fields.
+use arrow_array::{
+    Array, ArrayRef, Int32Array, Int64Array, RecordBatch, StringArray, TimestampMillisecondArray,
+};
+use arrow_schema::{Field, Schema, SchemaRef};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error::Error;
+use std::sync::Arc;
+
+use crate::sentence::Sentence;
+use crate::Page;
+
+/// Name, nullability and values of every column, in the order they are written
+fn columns(pages: &[Page]) -> Vec<(&'static str, bool, ArrayRef)> {
+    vec![
+        (
+            "id",
+            false,
+            Arc::new(Int64Array::from_iter_values(
+                pages.iter().map(|page| page.page_id),
+            )),
+        ),
+        (
+            "revision_id",
+            false,
+            Arc::new(Int64Array::from_iter_values(
+                pages.iter().map(|page| page.revision_id),
+            )),
+        ),
+        (
+            "timestamp",
+            false,
+            Arc::new(
+                TimestampMillisecondArray::from_iter_values(
+                    pages.iter().map(|page| page.timestamp),
+                )
+                .with_timezone("UTC"),
+            ),
+        ),
+        (
+            "title",
+            false,
+            Arc::new(StringArray::from_iter_values(
+                pages.iter().map(|page| &page.title),
+            )),
+        ),
+        (
+            "content",
+            false,
+            Arc::new(StringArray::from_iter_values(
+                pages.iter().map(|page| &page.content),
+            )),
+        ),
+        (
+            "abstract",
+            true,
+            Arc::new(StringArray::from_iter_values(
+                pages.iter().map(|page| &page.r#abstract),
+            )),
+        ),
+        (
+            "sections",
+            true,
+            Arc::new(StringArray::from_iter(
+                pages.iter().map(|page| json(&page.sections)),
+            )),
+        ),
+        (
+            "links",
+            true,
+            Arc::new(StringArray::from_iter(
+                pages.iter().map(|page| json(&page.links)),
+            )),
+        ),
+        (
+            "spans",
+            true,
+            Arc::new(StringArray::from_iter(
+                pages.iter().map(|page| json(&page.spans)),
+            )),
+        ),
+        (
+            "infoboxes",
+            true,
+            Arc::new(StringArray::from_iter(
+                pages.iter().map(|page| json(&page.infoboxes)),
+            )),
+        ),
+        (
+            "tables",
+            true,
+            Arc::new(StringArray::from_iter(
+                pages.iter().map(|page| json(&page.tables)),
+            )),
+        ),
+        (
+            "elements",
+            true,
+            Arc::new(StringArray::from_iter(
+                pages.iter().map(|page| json(&page.elements)),
+            )),
+        ),
+        (
+            "categories",
+            true,
+            Arc::new(StringArray::from_iter(
+                pages.iter().map(|page| json(&page.categories)),
+            )),
+        ),
+    ]
+}
+
+/// Nested values are stored as JSON strings, null if they were not extracted
+fn json<T: Serialize>(value: &Option<T>) -> Option<String> {
+    value
+        .as_ref()
+        .map(|value| serde_json::to_string(value).unwrap())
+}
+
+/// Parse a JSON column value written by `json`
+fn from_json<T: DeserializeOwned>(
+    values: Option<&StringArray>,
+    i: usize,
+) -> Result<Option<T>, Box<dyn Error>> {
+    match values {
+        Some(values) if values.is_valid(i) => Ok(Some(serde_json::from_str(values.value(i))?)),
+        _ => Ok(None),
+    }
+}
+
+/// Columns of the files written at sentence granularity
+fn sentence_columns(sentences: &[Sentence]) -> Vec<(&'static str, bool, ArrayRef)> {
+    vec![
+        (
+            "id",
+            false,
+            Arc::new(Int64Array::from_iter_values(
+                sentences.iter().map(|sentence| sentence.page_id),
+            )),
+        ),
+        (
+            "paragraph",
+            false,
+            Arc::new(Int32Array::from_iter_values(
+                sentences.iter().map(|sentence| sentence.paragraph as i32),
+            )),
+        ),
+        (
+            "sentence",
+            false,
+            Arc::new(Int32Array::from_iter_values(
+                sentences.iter().map(|sentence| sentence.sentence as i32),
+            )),
+        ),
+        (
+            "text",
+            false,
+            Arc::new(StringArray::from_iter_values(
+                sentences.iter().map(|sentence| &sentence.text),
+            )),
+        ),
+    ]
+}
+
+fn schema(columns: &[(&'static str, bool, ArrayRef)]) -> SchemaRef {
+    let fields: Vec<Field> = columns
+        .iter()
+        .map(|(name, nullable, values)| Field::new(*name, values.data_type().clone(), *nullable))
+        .collect();
+    Arc::new(Schema::new(fields))
+}
+
+fn record_batch(
+    columns: Vec<(&'static str, bool, ArrayRef)>,
+) -> Result<RecordBatch, Box<dyn Error>> {
+    let schema = schema(&columns);
+    let values = columns.into_iter().map(|(_, _, values)| values).collect();
+    Ok(RecordBatch::try_new(schema, values)?)
+}
+
+/// Arrow schema of the page files
+pub fn page_schema() -> SchemaRef {
+    schema(&columns(&[]))
+}
+
+pub fn to_record_batch(pages: &[Page]) -> Result<RecordBatch, Box<dyn Error>> {
+    record_batch(columns(pages))
+}
+
+pub fn sentences_to_record_batch(sentences: &[Sentence]) -> Result<RecordBatch, Box<dyn Error>> {
+    record_batch(sentence_columns(sentences))
+}
+
+/// Check that `schema` has exactly the columns of `page_schema`, in any order.
+/// Nullable columns may be missing from files written before they were added.
+/// Files written at sentence granularity are rejected, since their records are not pages.
+pub fn check_schema(schema: &Schema) -> Result<(), Box<dyn Error>> {
+    let is_sentence_file = ["paragraph", "sentence", "text"]
+        .iter()
+        .all(|name| schema.field_with_name(name).is_ok());
+    if is_sentence_file && schema.field_with_name("title").is_err() {
+        return Err(
+            "file has one record per sentence (written with `--granularity sentence`), \
+             only files with one record per page can be read"
+                .into(),
+        );
+    }
+    let expected = page_schema();
+    for field in expected.fields() {
+        match schema.field_with_name(field.name()) {
+            Ok(actual) if actual.data_type() != field.data_type() => {
+                return Err(format!(
+                    "column `{}` has type {}, expected {}",
+                    field.name(),
+                    actual.data_type(),
+                    field.data_type()
+                )
+                .into())
+            }
+            Ok(_) => {}
+            Err(_) if field.is_nullable() => {}
+            Err(_) => return Err(format!("missing column `{}`", field.name()).into()),
+        }
+    }
+    for field in schema.fields() {
+        if expected.field_with_name(field.name()).is_err() {
+            return Err(format!("unexpected column `{}`", field.name()).into());
+        }
+    }
+    Ok(())
+}
+
+/// Column `name` of `batch` as an array of type `T`, or `None` if it was not read
+fn column<'a, T: 'static>(
+    batch: &'a RecordBatch,
+    name: &str,
+) -> Result<Option<&'a T>, Box<dyn Error>> {
+    let Some(values) = batch.column_by_name(name) else {
+        return Ok(None);
+    };
+    match values.as_any().downcast_ref::<T>() {
+        Some(values) => Ok(Some(values)),
+        None => Err(format!("column `{name}` has unexpected type {}", values.data_type()).into()),
+    }
+}
+
+/// Pages of a batch from a file that passed `check_schema`.
+/// Fields whose column was left out of the projection are zero or empty.
+pub fn from_record_batch(batch: &RecordBatch) -> Result<Vec<Page>, Box<dyn Error>> {
+    let page_ids = column::<Int64Array>(batch, "id")?;
+    let revision_ids = column::<Int64Array>(batch, "revision_id")?;
+    let timestamps = column::<TimestampMillisecondArray>(batch, "timestamp")?;
+    let titles = column::<StringArray>(batch, "title")?;
+    let contents = column::<StringArray>(batch, "content")?;
+    let abstracts = column::<StringArray>(batch, "abstract")?;
+    let sections = column::<StringArray>(batch, "sections")?;
+    let links = column::<StringArray>(batch, "links")?;
+    let spans = column::<StringArray>(batch, "spans")?;
+    let infoboxes = column::<StringArray>(batch, "infoboxes")?;
+    let tables = column::<StringArray>(batch, "tables")?;
+    let elements = column::<StringArray>(batch, "elements")?;
+    let categories = column::<StringArray>(batch, "categories")?;
+    (0..batch.num_rows())
+        .map(|i| {
+            Ok(Page {
+                page_id: page_ids.map_or(0, |values| values.value(i)),
+                revision_id: revision_ids.map_or(0, |values| values.value(i)),
+                timestamp: timestamps.map_or(0, |values| values.value(i)),
+                title: titles.map_or_else(String::new, |values| values.value(i).to_string()),
+                content: contents.map_or_else(String::new, |values| values.value(i).to_string()),
+                r#abstract: abstracts
+                    .map_or_else(String::new, |values| values.value(i).to_string()),
+                sections: from_json(sections, i)?,
+                links: from_json(links, i)?,
+                spans: from_json(spans, i)?,
+                infoboxes: from_json(infoboxes, i)?,
+                tables: from_json(tables, i)?,
+                elements: from_json(elements, i)?,
+                categories: from_json(categories, i)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infobox::Infobox;
+    use crate::span::{Link, Span, SpanKind};
+    use crate::table::{Cell, Table};
+    use crate::{Element, ElementKind, Section};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn pages_round_trip() {
+        let page = Page {
+            page_id: 1,
+            revision_id: 2,
+            timestamp: 1_700_000_000_000,
+            title: "北京市".to_string(),
+            content: "北京是首都。\n人口众多。".to_string(),
+            r#abstract: "北京是首都。".to_string(),
+            sections: Some(vec![Section {
+                heading: "历史".to_string(),
+                level: 2,
+                paragraphs: vec!["人口众多。".to_string()],
+            }]),
+            links: Some(vec![Link {
+                target: "首都".to_string(),
+                anchor: "首都".to_string(),
+                offset: 3,
+            }]),
+            spans: Some(vec![
+                Span {
+                    kind: SpanKind::Bold,
+                    start: 0,
+                    end: 2,
+                    target: None,
+                },
+                Span {
+                    kind: SpanKind::Link,
+                    start: 3,
+                    end: 5,
+                    target: Some("首都".to_string()),
+                },
+            ]),
+            infoboxes: Some(vec![Infobox {
+                name: "Infobox city".to_string(),
+                fields: BTreeMap::from([("name".to_string(), "北京".to_string())]),
+            }]),
+            tables: Some(vec![Table {
+                caption: "人口".to_string(),
+                header_rows: 1,
+                rows: vec![vec![
+                    Cell {
+                        text: "年".to_string(),
+                        header: true,
+                    },
+                    Cell {
+                        text: "2020".to_string(),
+                        header: false,
+                    },
+                ]],
+            }]),
+            elements: Some(vec![Element {
+                kind: ElementKind::Li,
+                heading: "历史".to_string(),
+                text: "元朝".to_string(),
+            }]),
+            categories: Some(vec!["直辖市".to_string()]),
+        };
+        // Nothing extracted is stored as nulls and read back as `None`
+        let bare = Page {
+            page_id: 3,
+            title: "上海市".to_string(),
+            ..Page::default()
+        };
+        let pages = [page, bare];
+
+        let batch = to_record_batch(&pages).unwrap();
+        assert!(check_schema(&batch.schema()).is_ok());
+        let read = from_record_batch(&batch).unwrap();
+        let json = |pages: &[Page]| serde_json::to_value(pages).unwrap();
+        assert_eq!(json(&read), json(&pages));
+        assert!(read[1].sections.is_none() && read[1].categories.is_none());
+    }
+
+    #[test]
+    fn check_schema_rejects_sentence_files() {
+        assert!(check_schema(&page_schema()).is_ok());
+        let sentences = sentences_to_record_batch(&[]).unwrap();
+        let error = check_schema(&sentences.schema()).unwrap_err().to_string();
+        assert!(error.contains("--granularity sentence"), "{error}");
+    }
+}
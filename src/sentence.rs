@@ -0,0 +1,133 @@
+// Splits the cleaned text of a page into sentences
+use serde::Serialize;
+
+use crate::Page;
+
+/// Punctuation that ends a sentence
+const TERMINATORS: &[char] = &['。', '！', '？', '；', '!', '?', '…'];
+
+/// Closing quotes and brackets that belong to the sentence they follow
+const CLOSING: &[char] = &['」', '』', '”', '’', '"', '\'', '）', ')', '》', '】', '〕'];
+
+/// One sentence of a page, written instead of the page at sentence granularity
+#[derive(Debug, Clone, Serialize)]
+pub struct Sentence {
+    #[serde(rename = "id")]
+    pub page_id: i64,
+    /// Index of the paragraph, i.e. the non-empty line of `content`, within the page
+    pub paragraph: usize,
+    /// Index of the sentence within the paragraph
+    pub sentence: usize,
+    pub text: String,
+}
+
+/// Split a paragraph after `。！？；`, ellipses and `!?`, keeping closing quotes such as `」』”`
+/// with the sentence they end. A `.` only ends a sentence before whitespace and after a
+/// non-digit, so `3.14` and `1.` list numbers are kept.
+pub fn split_sentences(paragraph: &str) -> Vec<&str> {
+    let chars: Vec<(usize, char)> = paragraph.char_indices().collect();
+    let mut sentences = vec![];
+    let mut start = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let (_, c) = chars[i];
+        let ends = TERMINATORS.contains(&c)
+            || (c == '.'
+                && i > 0
+                && !chars[i - 1].1.is_ascii_digit()
+                && chars
+                    .get(i + 1)
+                    .is_none_or(|(_, next)| next.is_whitespace()));
+        i += 1;
+        if !ends {
+            continue;
+        }
+        // Runs like `？！` or `……` and closing quotes end the same sentence
+        while chars
+            .get(i)
+            .is_some_and(|(_, c)| TERMINATORS.contains(c) || CLOSING.contains(c))
+        {
+            i += 1;
+        }
+        let end = chars.get(i).map_or(paragraph.len(), |(index, _)| *index);
+        push_trimmed(&mut sentences, &paragraph[start..end]);
+        start = end;
+    }
+    push_trimmed(&mut sentences, &paragraph[start..]);
+    sentences
+}
+
+fn push_trimmed<'a>(sentences: &mut Vec<&'a str>, sentence: &'a str) {
+    let sentence = sentence.trim();
+    if !sentence.is_empty() {
+        sentences.push(sentence);
+    }
+}
+
+/// Sentences of every paragraph of a page
+pub fn sentences(page: &Page) -> Vec<Sentence> {
+    page.content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .flat_map(|(paragraph, line)| {
+            split_sentences(line)
+                .into_iter()
+                .enumerate()
+                .map(move |(sentence, text)| Sentence {
+                    page_id: page.page_id,
+                    paragraph,
+                    sentence,
+                    text: text.to_string(),
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_after_terminators() {
+        assert_eq!(
+            split_sentences("他说：「走吧！」我们走了。真的吗？？是的……好"),
+            vec![
+                "他说：「走吧！」",
+                "我们走了。",
+                "真的吗？？",
+                "是的……",
+                "好"
+            ]
+        );
+    }
+
+    #[test]
+    fn dots_in_numbers_do_not_split() {
+        assert_eq!(
+            split_sentences("圆周率约为3.14159. 1. 第一项 It ends. Next"),
+            vec!["圆周率约为3.14159. 1. 第一项 It ends.", "Next"]
+        );
+    }
+
+    #[test]
+    fn sentences_are_numbered_per_paragraph() {
+        let page = Page {
+            page_id: 1,
+            content: "一。二。\n\n三".to_string(),
+            ..Page::default()
+        };
+        let numbered: Vec<_> = sentences(&page)
+            .into_iter()
+            .map(|sentence| (sentence.paragraph, sentence.sentence, sentence.text))
+            .collect();
+        assert_eq!(
+            numbered,
+            vec![
+                (0, 0, "一。".to_string()),
+                (0, 1, "二。".to_string()),
+                (1, 0, "三".to_string()),
+            ]
+        );
+    }
+}
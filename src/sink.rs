@@ -0,0 +1,378 @@
+// Output formats that batches of parsed pages can be written to
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::sentence::{self, split_sentences};
+use crate::{Granularity, Page, PartWriter};
+
+/// Receives the parsed pages of one variant in batches, on the writer thread
+pub trait Sink: Send {
+    fn write(&mut self, pages: &[Page]) -> Result<(), Box<dyn Error>>;
+
+    /// Flush everything after the last batch
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+impl Sink for PartWriter {
+    fn write(&mut self, pages: &[Page]) -> Result<(), Box<dyn Error>> {
+        PartWriter::write(self, pages)
+    }
+}
+
+/// Compression of JSON Lines and wikiextractor shards
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    None,
+    /// Adds `.gz` to the file names
+    Gzip,
+    /// Adds `.zst` to the file names
+    Zstd,
+}
+
+impl Compression {
+    fn suffix(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+/// An open shard file, compressed as it is written
+enum Shard {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Shard {
+    fn create(path: &Path, compression: Compression) -> io::Result<Shard> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(match compression {
+            Compression::None => Shard::Plain(file),
+            Compression::Gzip => Shard::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+            Compression::Zstd => Shard::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    /// Write the compression trailer and flush the file
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Shard::Plain(mut file) => file.flush(),
+            Shard::Gzip(encoder) => encoder.finish()?.flush(),
+            Shard::Zstd(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for Shard {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Shard::Plain(file) => file.write(buf),
+            Shard::Gzip(encoder) => encoder.write(buf),
+            Shard::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Shard::Plain(file) => file.flush(),
+            Shard::Gzip(encoder) => encoder.flush(),
+            Shard::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Writes records to numbered shard files in a directory, starting a new shard once the current
+/// one holds `shard_size` bytes before compression
+struct ShardWriter {
+    dir: PathBuf,
+    compression: Compression,
+    shard_size: u64,
+    /// Path of shard `n` relative to `dir`, without the compression suffix
+    shard_path: fn(usize) -> String,
+    num_shards: usize,
+    shard: Option<Shard>,
+    /// Uncompressed bytes written to the current shard
+    shard_bytes: u64,
+}
+
+impl ShardWriter {
+    fn create(
+        dir: &Path,
+        compression: Compression,
+        shard_size: u64,
+        shard_path: fn(usize) -> String,
+    ) -> Result<ShardWriter, Box<dyn Error>> {
+        let first = dir.join(shard_path(0) + compression.suffix());
+        if first.exists() {
+            return Err(format!("{} already exists, remove it first", first.display()).into());
+        }
+        fs::create_dir_all(dir)?;
+        Ok(ShardWriter {
+            dir: dir.to_path_buf(),
+            compression,
+            shard_size,
+            shard_path,
+            num_shards: 0,
+            shard: None,
+            shard_bytes: 0,
+        })
+    }
+
+    fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        if self.shard.is_none() || self.shard_bytes >= self.shard_size {
+            self.next_shard()?;
+        }
+        self.shard.as_mut().unwrap().write_all(record)?;
+        self.shard_bytes += record.len() as u64;
+        Ok(())
+    }
+
+    fn next_shard(&mut self) -> io::Result<()> {
+        self.finish()?;
+        let path = self
+            .dir
+            .join((self.shard_path)(self.num_shards) + self.compression.suffix());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.shard = Some(Shard::create(&path, self.compression)?);
+        self.num_shards += 1;
+        self.shard_bytes = 0;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.shard.take() {
+            Some(shard) => shard.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes one JSON object per page or sentence to `part-00000.jsonl`, `part-00001.jsonl`, ...
+/// in a directory
+pub struct JsonlSink {
+    shards: ShardWriter,
+    granularity: Granularity,
+}
+
+impl JsonlSink {
+    pub fn create(
+        dir: &Path,
+        granularity: Granularity,
+        compression: Compression,
+        shard_size: u64,
+    ) -> Result<JsonlSink, Box<dyn Error>> {
+        let shards = ShardWriter::create(dir, compression, shard_size, |n| {
+            format!("part-{n:05}.jsonl")
+        })?;
+        Ok(JsonlSink {
+            shards,
+            granularity,
+        })
+    }
+}
+
+impl Sink for JsonlSink {
+    fn write(&mut self, pages: &[Page]) -> Result<(), Box<dyn Error>> {
+        for page in pages {
+            let mut lines = vec![];
+            match self.granularity {
+                Granularity::Paragraph => serde_json::to_writer(&mut lines, page)?,
+                Granularity::Sentence => {
+                    for (i, sentence) in sentence::sentences(page).iter().enumerate() {
+                        if i > 0 {
+                            lines.push(b'\n');
+                        }
+                        serde_json::to_writer(&mut lines, sentence)?;
+                    }
+                }
+            }
+            if !lines.is_empty() {
+                lines.push(b'\n');
+                self.shards.write(&lines)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(self.shards.finish()?)
+    }
+}
+
+/// Writes pages in the layout of attardi's wikiextractor, as `<doc>` blocks in `AA/wiki_00`,
+/// `AA/wiki_01`, ..., `AA/wiki_99`, `AB/wiki_00`, ...
+pub struct WikiExtractorSink {
+    shards: ShardWriter,
+    granularity: Granularity,
+    url_base: String,
+}
+
+impl WikiExtractorSink {
+    pub fn create(
+        dir: &Path,
+        granularity: Granularity,
+        compression: Compression,
+        shard_size: u64,
+        url_base: &str,
+    ) -> Result<WikiExtractorSink, Box<dyn Error>> {
+        let shards = ShardWriter::create(dir, compression, shard_size, wikiextractor_shard_path)?;
+        Ok(WikiExtractorSink {
+            shards,
+            granularity,
+            url_base: url_base.to_string(),
+        })
+    }
+}
+
+/// Path of the `n`-th shard like wikiextractor names them, a hundred per directory
+fn wikiextractor_shard_path(n: usize) -> String {
+    let group = n / 100;
+    let first = (b'A' + (group / 26) as u8) as char;
+    let second = (b'A' + (group % 26) as u8) as char;
+    format!("{first}{second}/wiki_{:02}", n % 100)
+}
+
+impl Sink for WikiExtractorSink {
+    fn write(&mut self, pages: &[Page]) -> Result<(), Box<dyn Error>> {
+        for page in pages {
+            let content = match self.granularity {
+                Granularity::Paragraph => page.content.clone(),
+                // One sentence per line
+                Granularity::Sentence => page
+                    .content
+                    .lines()
+                    .flat_map(split_sentences)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            };
+            // Same header, title line and footer as wikiextractor, which does not escape the title
+            let doc = format!(
+                "<doc id=\"{id}\" url=\"{url_base}?curid={id}\" title=\"{title}\">\n{title}\n\n{content}\n</doc>\n",
+                id = page.page_id,
+                url_base = self.url_base,
+                title = page.title,
+                content = content,
+            );
+            self.shards.write(doc.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(self.shards.finish()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn page(page_id: i64) -> Page {
+        Page {
+            page_id,
+            title: format!("标题{page_id}"),
+            content: "第一段。\n第二段。".to_string(),
+            ..Page::default()
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Decompressed content of a shard
+    fn read_shard(path: &Path, compression: Compression) -> String {
+        let file = File::open(path).unwrap();
+        let mut text = String::new();
+        match compression {
+            Compression::None => io::BufReader::new(file).read_to_string(&mut text),
+            Compression::Gzip => GzDecoder::new(file).read_to_string(&mut text),
+            Compression::Zstd => zstd::Decoder::new(file).unwrap().read_to_string(&mut text),
+        }
+        .unwrap();
+        text
+    }
+
+    #[test]
+    fn jsonl_shards_roll_over_and_are_compressed() {
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let dir = temp_dir(&format!("jsonl-sink-{compression:?}"));
+            let mut sink =
+                JsonlSink::create(&dir, Granularity::Paragraph, compression, 150).unwrap();
+            sink.write(&[page(1), page(2)]).unwrap();
+            sink.write(&[page(3)]).unwrap();
+            sink.finish().unwrap();
+            assert!(JsonlSink::create(&dir, Granularity::Paragraph, compression, 150).is_err());
+
+            // A shard is only full once a record goes past the size, so each holds two pages
+            let suffix = compression.suffix();
+            let ids: Vec<Vec<i64>> = (0..2)
+                .map(|n| {
+                    let path = dir.join(format!("part-{n:05}.jsonl{suffix}"));
+                    read_shard(&path, compression)
+                        .lines()
+                        .map(|line| {
+                            let page: serde_json::Value = serde_json::from_str(line).unwrap();
+                            assert_eq!(page["content"], "第一段。\n第二段。");
+                            page["id"].as_i64().unwrap()
+                        })
+                        .collect()
+                })
+                .collect();
+            assert_eq!(ids, vec![vec![1, 2], vec![3]]);
+            assert!(!dir.join(format!("part-00002.jsonl{suffix}")).exists());
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn wikiextractor_shards_are_named_like_wikiextractor() {
+        assert_eq!(wikiextractor_shard_path(0), "AA/wiki_00");
+        assert_eq!(wikiextractor_shard_path(99), "AA/wiki_99");
+        assert_eq!(wikiextractor_shard_path(100), "AB/wiki_00");
+        assert_eq!(wikiextractor_shard_path(2601), "BA/wiki_01");
+
+        let dir = temp_dir("wikiextractor-sink");
+        let mut sink = WikiExtractorSink::create(
+            &dir,
+            Granularity::Paragraph,
+            Compression::Gzip,
+            1,
+            "https://zh.wikipedia.org/wiki",
+        )
+        .unwrap();
+        let pages: Vec<Page> = (0..101).map(page).collect();
+        sink.write(&pages).unwrap();
+        sink.finish().unwrap();
+
+        assert_eq!(
+            read_shard(&dir.join("AA/wiki_00.gz"), Compression::Gzip),
+            concat!(
+                "<doc id=\"0\" url=\"https://zh.wikipedia.org/wiki?curid=0\" title=\"标题0\">\n",
+                "标题0\n\n第一段。\n第二段。\n</doc>\n",
+            )
+        );
+        assert!(
+            read_shard(&dir.join("AA/wiki_99.gz"), Compression::Gzip).starts_with("<doc id=\"99\"")
+        );
+        assert!(read_shard(&dir.join("AB/wiki_00.gz"), Compression::Gzip)
+            .starts_with("<doc id=\"100\""));
+        assert!(!dir.join("AB/wiki_01.gz").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
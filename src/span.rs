@@ -0,0 +1,206 @@
+// Bold, italic and link markup of the rendered HTML, located in the cleaned text
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+use crate::tracked::TrackedText;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpanKind {
+    Bold,
+    Italic,
+    Link,
+}
+
+/// A `<b>`, `<i>` or internal `<a>` inside a `<p>`, before cleaning
+#[derive(Debug, Clone)]
+pub struct RawSpan {
+    pub kind: SpanKind,
+    /// Title of the linked page for links
+    pub target: Option<String>,
+    /// Byte range of the span in the raw text of all paragraphs
+    pub range: Range<usize>,
+}
+
+/// An outgoing wiki link of a page
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Link {
+    pub target: String,
+    /// Text of the link as it appears in `content`
+    pub anchor: String,
+    /// Offset of the anchor in `content`, in chars
+    pub offset: usize,
+}
+
+/// A bold, italic or link annotation of `content`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    pub kind: SpanKind,
+    /// Char offset of the first char of the span in `content`
+    pub start: usize,
+    /// Char offset just past the last char of the span
+    pub end: usize,
+    /// Title of the linked page for links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+/// Where a `RawSpan` ended up in the cleaned text, in chars
+pub struct Located<'a> {
+    pub span: &'a RawSpan,
+    pub start: usize,
+    pub end: usize,
+    pub anchor: String,
+}
+
+/// Title of the page an internal link points to, `None` for external links and anchors
+pub fn link_target(href: &str) -> Option<String> {
+    let title = match href.strip_prefix("/index.php?") {
+        // Links to missing pages look like `/index.php?title=…&action=edit&redlink=1`
+        Some(query) => query
+            .split('&')
+            .find_map(|param| param.strip_prefix("title="))?,
+        None => href
+            .strip_prefix("/index.php/")
+            .or_else(|| href.strip_prefix("/wiki/"))?,
+    };
+    let title = title.split('#').next().unwrap_or_default();
+    let title = percent_decode(title).replace('_', " ");
+    (!title.is_empty()).then_some(title)
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Map each span to the cleaned text it ended up as. Spans whose text was removed by cleaning
+/// are left out.
+pub fn locate<'a>(text: &TrackedText, spans: &'a [RawSpan]) -> Vec<Located<'a>> {
+    let mut located = vec![];
+    // Byte and char offsets of the previous span's start, as spans start in document order
+    let mut cursor = (0, 0);
+    for span in spans {
+        let Some(range) = text.find_origin(span.range.clone()) else {
+            continue;
+        };
+        let anchor = text.text[range.clone()].to_string();
+        let start = cursor.1 + text.text[cursor.0..range.start].chars().count();
+        cursor = (range.start, start);
+        located.push(Located {
+            span,
+            start,
+            end: start + anchor.chars().count(),
+            anchor,
+        });
+    }
+    located
+}
+
+/// Outgoing links of a page with their position in the cleaned text
+pub fn links(text: &TrackedText, spans: &[RawSpan]) -> Vec<Link> {
+    locate(text, spans)
+        .into_iter()
+        .filter(|located| located.span.kind == SpanKind::Link)
+        .filter_map(|located| {
+            Some(Link {
+                target: located.span.target.clone()?,
+                anchor: located.anchor,
+                offset: located.start,
+            })
+        })
+        .collect()
+}
+
+/// Bold, italic and link spans of a page with their position in the cleaned text
+pub fn spans(text: &TrackedText, spans: &[RawSpan]) -> Vec<Span> {
+    locate(text, spans)
+        .into_iter()
+        .map(|located| Span {
+            kind: located.span.kind,
+            start: located.start,
+            end: located.end,
+            target: located.span.target.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{blocks_to_text, html_to_blocks};
+
+    fn page(html: &str) -> (TrackedText, Vec<RawSpan>) {
+        let (blocks, spans) = html_to_blocks(html, &[]).unwrap();
+        (blocks_to_text(&blocks, false, true), spans)
+    }
+
+    #[test]
+    fn link_offsets_are_where_the_link_is() {
+        let (text, raw) = page(r#"<p>北京市是首都，<a href="/index.php/北京">北京</a>很大。</p>"#);
+        assert_eq!(text.text, "北京市是首都，北京很大。");
+        assert_eq!(
+            links(&text, &raw),
+            vec![Link {
+                target: "北京".to_string(),
+                anchor: "北京".to_string(),
+                offset: 7,
+            }]
+        );
+    }
+
+    #[test]
+    fn spans_of_repeated_words() {
+        let (text, raw) =
+            page("<p>水是水，<b>水</b>不是<i>水</i>。</p><h2>历史</h2><p>水<b>水</b></p>");
+        assert_eq!(text.text, "水是水，水不是水。\n水水");
+        let located: Vec<_> = spans(&text, &raw)
+            .into_iter()
+            .map(|span| (span.kind, span.start, span.end))
+            .collect();
+        assert_eq!(
+            located,
+            vec![
+                (SpanKind::Bold, 4, 5),
+                (SpanKind::Italic, 7, 8),
+                (SpanKind::Bold, 11, 12),
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_and_removed_spans() {
+        let (text, raw) = page(concat!(
+            r#"<p><b>上海 <a href="/index.php/上海市">上海市</a></b>"#,
+            r#"（<i>Shanghai</i>，沪）是城市。</p>"#,
+        ));
+        assert_eq!(text.text, "上海 上海市是城市。");
+        let located: Vec<_> = spans(&text, &raw)
+            .into_iter()
+            .map(|span| (span.kind, span.start, span.end))
+            .collect();
+        // The italic text was in the removed parentheses
+        assert_eq!(
+            located,
+            vec![(SpanKind::Bold, 0, 6), (SpanKind::Link, 3, 6)]
+        );
+    }
+}
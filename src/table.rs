@@ -0,0 +1,308 @@
+// Tables of the rendered HTML as grids of cleaned cell text
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+use crate::clean_text;
+
+/// Tables with these classes are navigation or maintenance boxes rather than content
+const SKIPPED_CLASSES: &[&str] = &["navbox", "metadata"];
+
+/// Largest `colspan` and `rowspan` followed, the same limits as MediaWiki's sanitizer
+const MAX_COLSPAN: usize = 1000;
+const MAX_ROWSPAN: usize = 65534;
+
+/// A table of a page, with cells spanning several rows or columns repeated in each of them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Table {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub caption: String,
+    /// Number of rows at the top that only have header cells
+    pub header_rows: usize,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cell {
+    pub text: String,
+    /// Whether the cell is a `<th>`
+    pub header: bool,
+}
+
+/// A cell as written in the HTML, before spans are expanded
+struct RawCell {
+    cell: Cell,
+    rowspan: usize,
+    colspan: usize,
+}
+
+/// A table whose end tag has not been read yet
+struct OpenTable {
+    /// Index of the table in the output, so tables are in the order they start
+    index: usize,
+    skipped: bool,
+    caption: String,
+    rows: Vec<Vec<RawCell>>,
+    in_caption: bool,
+}
+
+/// Tables of a page in the order they start, including ones nested in a cell of another table.
+/// Footnote markers are left out of the cells, and tables without cells are dropped.
+pub fn tables(html: &str) -> Vec<Table> {
+    let mut reader = Reader::from_reader(Cursor::new(html));
+    reader.trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut tables: Vec<Option<Table>> = vec![];
+    let mut open: Vec<OpenTable> = vec![];
+    // Depth inside footnote markers, edit links and styles
+    let mut skip_depth = 0;
+
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(_)) if skip_depth > 0 => skip_depth += 1,
+            Ok(Event::Start(ref e)) => {
+                let class = attribute(e, b"class").unwrap_or_default();
+                let is_reference = e.name().as_ref() == b"sup" && class.contains("reference");
+                match e.name().as_ref() {
+                    _ if is_reference || class.contains("mw-editsection") => skip_depth = 1,
+                    b"style" | b"script" => skip_depth = 1,
+                    b"table" => {
+                        open.push(OpenTable {
+                            index: tables.len(),
+                            // Tables inside a skipped box are skipped too
+                            skipped: open.last().is_some_and(|table| table.skipped)
+                                || SKIPPED_CLASSES
+                                    .iter()
+                                    .any(|skipped| class.contains(skipped)),
+                            caption: String::new(),
+                            rows: vec![],
+                            in_caption: false,
+                        });
+                        tables.push(None);
+                    }
+                    b"caption" => {
+                        if let Some(table) = open.last_mut() {
+                            table.in_caption = true;
+                        }
+                    }
+                    b"tr" => {
+                        if let Some(table) = open.last_mut() {
+                            table.rows.push(vec![]);
+                        }
+                    }
+                    name @ (b"th" | b"td") => {
+                        if let Some(table) = open.last_mut() {
+                            if table.rows.is_empty() {
+                                table.rows.push(vec![]);
+                            }
+                            let span = |key: &[u8], max: usize| {
+                                attribute(e, key)
+                                    .and_then(|value| value.trim().parse::<usize>().ok())
+                                    .map_or(1, |span| span.clamp(1, max))
+                            };
+                            table.rows.last_mut().unwrap().push(RawCell {
+                                cell: Cell {
+                                    text: String::new(),
+                                    header: name == b"th",
+                                },
+                                rowspan: span(b"rowspan", MAX_ROWSPAN),
+                                colspan: span(b"colspan", MAX_COLSPAN),
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Event::End(_)) if skip_depth > 0 => skip_depth -= 1,
+            Ok(Event::End(ref e)) => match e.name().as_ref() {
+                b"table" => {
+                    if let Some(table) = open.pop() {
+                        if !table.skipped {
+                            let index = table.index;
+                            tables[index] = Some(expand(table));
+                        }
+                    }
+                }
+                b"caption" => {
+                    if let Some(table) = open.last_mut() {
+                        table.in_caption = false;
+                    }
+                }
+                // Paragraphs and list items in a cell are kept on separate lines
+                b"p" | b"li" | b"dd" | b"dt" | b"div" => push_text(&mut open, "\n"),
+                _ => {}
+            },
+            Ok(Event::Empty(ref e)) if skip_depth == 0 && e.name().as_ref() == b"br" => {
+                push_text(&mut open, "\n");
+            }
+            Ok(Event::Text(e)) if skip_depth == 0 => {
+                push_text(&mut open, &e.unescape().unwrap_or_default());
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    tables
+        .into_iter()
+        .flatten()
+        .filter(|table| !table.rows.is_empty())
+        .collect()
+}
+
+fn attribute(e: &BytesStart, key: &[u8]) -> Option<String> {
+    e.attributes()
+        .flatten()
+        .find(|attribute| attribute.key.as_ref() == key)
+        .map(|attribute| String::from_utf8_lossy(&attribute.value).into_owned())
+}
+
+/// Add text to the caption or the last cell of the innermost open table
+fn push_text(open: &mut [OpenTable], text: &str) {
+    let Some(table) = open.last_mut() else {
+        return;
+    };
+    if table.in_caption {
+        table.caption.push_str(text);
+    } else if let Some(cell) = table.rows.last_mut().and_then(|row| row.last_mut()) {
+        cell.cell.text.push_str(text);
+    }
+}
+
+/// Cleaned text of each non-empty line of a cell or caption
+fn clean_cell(text: &str) -> String {
+    text.lines()
+        .map(|line| clean_text(line).trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Place the cells of a table on a grid, repeating cells with a `rowspan` or `colspan`
+fn expand(table: OpenTable) -> Table {
+    let mut rows = vec![];
+    // Cell continuing down each column and the number of rows it still covers
+    let mut carried: Vec<Option<(usize, Cell)>> = vec![];
+    for raw_row in table.rows {
+        let mut row = vec![];
+        let mut raw_cells = raw_row.into_iter();
+        let mut column = 0;
+        loop {
+            if let Some(Some((rows_left, cell))) = carried.get_mut(column) {
+                row.push(cell.clone());
+                *rows_left -= 1;
+                if *rows_left == 0 {
+                    carried[column] = None;
+                }
+                column += 1;
+                continue;
+            }
+            let Some(raw) = raw_cells.next() else {
+                // Pad a short row up to the cells still continuing from above, so they stay in
+                // their column and are counted down on every row
+                if carried
+                    .get(column..)
+                    .is_some_and(|rest| rest.iter().any(Option::is_some))
+                {
+                    row.push(Cell {
+                        text: String::new(),
+                        header: false,
+                    });
+                    column += 1;
+                    continue;
+                }
+                break;
+            };
+            let cell = Cell {
+                text: clean_cell(&raw.cell.text),
+                header: raw.cell.header,
+            };
+            for _ in 0..raw.colspan {
+                if raw.rowspan > 1 {
+                    if carried.len() <= column {
+                        carried.resize(column + 1, None);
+                    }
+                    carried[column] = Some((raw.rowspan - 1, cell.clone()));
+                }
+                row.push(cell.clone());
+                column += 1;
+            }
+        }
+        if !row.is_empty() {
+            rows.push(row);
+        }
+    }
+    let header_rows = rows
+        .iter()
+        .take_while(|row| row.iter().all(|cell| cell.header))
+        .count();
+    Table {
+        caption: clean_cell(&table.caption),
+        header_rows,
+        rows,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(table: &Table) -> Vec<Vec<&str>> {
+        table
+            .rows
+            .iter()
+            .map(|row| row.iter().map(|cell| cell.text.as_str()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn spans_are_expanded() {
+        let html = concat!(
+            "<table><caption>表</caption>",
+            "<tr><th>年</th><th colspan=\"2\">事</th></tr>",
+            "<tr><td rowspan=\"2\">2000</td><td>a</td><td>b</td></tr>",
+            "<tr><td colspan=\"2\">c</td></tr>",
+            "</table>",
+        );
+        let tables = tables(html);
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].caption, "表");
+        assert_eq!(tables[0].header_rows, 1);
+        assert_eq!(
+            texts(&tables[0]),
+            vec![
+                vec!["年", "事", "事"],
+                vec!["2000", "a", "b"],
+                vec!["2000", "c", "c"],
+            ]
+        );
+    }
+
+    #[test]
+    fn short_rows_keep_carried_cells_in_their_column() {
+        let html = concat!(
+            "<table>",
+            "<tr><td>a</td><td>b</td><td rowspan=\"2\">c</td></tr>",
+            "<tr><td>x</td></tr>",
+            "<tr><td>p</td><td>q</td><td>r</td></tr>",
+            "</table>",
+        );
+        assert_eq!(
+            texts(&tables(html)[0]),
+            vec![vec!["a", "b", "c"], vec!["x", "", "c"], vec!["p", "q", "r"]]
+        );
+    }
+
+    #[test]
+    fn navigation_boxes_are_skipped() {
+        let html = concat!(
+            "<table class=\"navbox\"><tr><td><table><tr><td>a</td></tr></table></td></tr></table>",
+            "<table><tr><td>b<sup class=\"reference\">[1]</sup></td></tr></table>",
+        );
+        let tables = tables(html);
+        assert_eq!(tables.len(), 1);
+        assert_eq!(texts(&tables[0]), vec![vec!["b"]]);
+    }
+}
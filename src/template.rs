@@ -0,0 +1,877 @@
+// Expands `{{templates}}` in wikitext using the `Template:` pages collected by `extract_templates`.
+// Only core parser functions are supported, Lua modules called through `#invoke` expand to nothing.
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use regex::Regex;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{LazyLock, OnceLock};
+
+use crate::wikitext::{COMMENT, DROPPED_TAGS};
+
+/// Deepest nesting of template calls, the same limit as MediaWiki's `$wgMaxTemplateDepth`
+const MAX_DEPTH: usize = 40;
+
+/// Deepest nesting of braces within a page or template, deeper braces are plain text
+const MAX_NESTING: usize = 200;
+
+/// Number of `#REDIRECT`s followed when looking up a template
+const MAX_REDIRECTS: usize = 5;
+
+/// Bytes of template output per page after which templates expand to nothing, the same limit as
+/// MediaWiki's `$wgMaxArticleSize` puts on the post-expand include size
+const MAX_EXPANDED_SIZE: usize = 2 * 1024 * 1024;
+
+/// Tags whose content is not expanded, in addition to the dropped tags of the wikitext renderer
+const PROTECTED_TAGS: &[&str] = &["nowiki", "pre"];
+
+// Patterns used for every page, compiled once
+static ONLYINCLUDE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<onlyinclude>(.*?)</onlyinclude>").unwrap());
+static NOINCLUDE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<noinclude>.*?(</noinclude>|$)").unwrap());
+static INCLUDEONLY_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"</?includeonly>").unwrap());
+static INCLUDEONLY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<includeonly>.*?(</includeonly>|$)").unwrap());
+static INCLUSION_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"</?(noinclude|onlyinclude)>").unwrap());
+static PAIRED_PROTECTED_TAG: LazyLock<Regex> = LazyLock::new(|| {
+    let tags = PROTECTED_TAGS
+        .iter()
+        .chain(DROPPED_TAGS)
+        .copied()
+        .collect::<Vec<_>>()
+        .join("|");
+    Regex::new(&format!(r"(?is)<({tags})(\s[^>]*)?>.*?</({tags})\s*>")).unwrap()
+});
+static STRIP_MARKER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new("\u{7f}UNIQ-([0-9]+)-QINU\u{7f}").unwrap());
+
+pub struct Templates {
+    /// Templates keyed by normalized name without namespace
+    bodies: HashMap<String, Template>,
+    /// Templates that redirect to another template
+    redirects: HashMap<String, String>,
+}
+
+impl Templates {
+    /// Load all `Template:` pages from a MediaWiki XML file such as `data/zhwiki-templates.xml`
+    pub fn load(xml_filename: &str) -> Result<Templates, Box<dyn std::error::Error>> {
+        let file = File::open(xml_filename)?;
+        let mut reader = Reader::from_reader(BufReader::new(file));
+        let mut buf = Vec::new();
+
+        let redirect = Regex::new(r"(?i)^\s*#(redirect|重定向)\s*\[\[([^\]|]+)").unwrap();
+        let mut templates = Templates {
+            bodies: HashMap::new(),
+            redirects: HashMap::new(),
+        };
+
+        let mut inside_title = false;
+        let mut inside_text = false;
+        let mut current_title: Option<String> = None;
+        let mut current_text = String::new();
+
+        loop {
+            match reader.read_event_into(&mut buf) {
+                Ok(Event::Start(ref e)) => match e.name().as_ref() {
+                    b"title" => inside_title = true,
+                    b"text" => inside_text = true,
+                    _ => {}
+                },
+                Ok(Event::End(ref e)) => match e.name().as_ref() {
+                    b"title" => inside_title = false,
+                    b"text" => inside_text = false,
+                    b"page" => {
+                        if let Some(title) = current_title.take() {
+                            if let Some(caps) = redirect.captures(&current_text) {
+                                templates
+                                    .redirects
+                                    .insert(normalize_name(&title), normalize_name(&caps[2]));
+                            } else {
+                                templates
+                                    .bodies
+                                    .insert(normalize_name(&title), Template::new(&current_text));
+                            }
+                        }
+                        current_text.clear();
+                    }
+                    _ => {}
+                },
+                Ok(Event::Text(e)) => {
+                    if inside_title {
+                        let title = e.unescape()?;
+                        if title.starts_with("Template:") {
+                            current_title = Some(title.to_string());
+                        }
+                    } else if inside_text {
+                        current_text.push_str(&e.unescape()?);
+                    }
+                }
+                Ok(Event::Eof) => break,
+                Err(e) => return Err(From::from(e)),
+                _ => {}
+            }
+            buf.clear();
+        }
+        Ok(templates)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bodies.len()
+    }
+
+    fn get(&self, name: &str) -> Option<&Template> {
+        let mut name = normalize_name(name);
+        for _ in 0..MAX_REDIRECTS {
+            match self.redirects.get(&name) {
+                Some(target) => name = target.clone(),
+                None => break,
+            }
+        }
+        self.bodies.get(&name)
+    }
+
+    /// Expand all templates, parameters and parser functions in the wikitext of an article.
+    /// The content of `<nowiki>`, `<pre>` and extension tags such as `<math>` is left as is.
+    pub fn expand(&self, wikitext: &str) -> String {
+        let text = for_page_view(wikitext);
+        let (text, stripped) = strip_tags(&text);
+        let nodes = parse(&text);
+        let expanded = self.expand_nodes(&nodes, &HashMap::new(), 0, &Cell::new(0));
+        unstrip(&expanded, &stripped)
+    }
+
+    fn expand_nodes(
+        &self,
+        nodes: &[Node],
+        args: &Args,
+        depth: usize,
+        size: &Cell<usize>,
+    ) -> String {
+        nodes
+            .iter()
+            .map(|node| self.expand_node(node, args, depth, size))
+            .collect()
+    }
+
+    fn expand_node(&self, node: &Node, args: &Args, depth: usize, size: &Cell<usize>) -> String {
+        match node {
+            Node::Text(text) => text.clone(),
+            Node::Param(parts) => {
+                let name = self.expand_nodes(&parts[0], args, depth, size);
+                match args.get(name.trim()) {
+                    Some(value) => value.clone(),
+                    None if parts.len() > 1 => self.expand_parts(&parts[1..], args, depth, size),
+                    None => format!("{{{{{{{name}}}}}}}"),
+                }
+            }
+            Node::Template(parts) => {
+                if depth >= MAX_DEPTH || size.get() > MAX_EXPANDED_SIZE {
+                    return String::new();
+                }
+                let name = self.expand_nodes(&parts[0], args, depth, size);
+                let name = name.trim();
+                if let Some(output) = self.parser_function(name, &parts[1..], args, depth, size) {
+                    size.set(size.get() + output.len());
+                    return output;
+                }
+                let Some(template) = self.get(name) else {
+                    return String::new();
+                };
+                let mut template_args = HashMap::new();
+                let mut position = 0;
+                for part in &parts[1..] {
+                    match split_named_arg(part) {
+                        Some((key, value)) => {
+                            let key = self.expand_nodes(&key, args, depth, size);
+                            let value = self.expand_nodes(&value, args, depth, size);
+                            template_args.insert(key.trim().to_string(), value.trim().to_string());
+                        }
+                        None => {
+                            position += 1;
+                            let value = self.expand_nodes(part, args, depth, size);
+                            template_args.insert(position.to_string(), value);
+                        }
+                    }
+                }
+                let output = self.expand_nodes(template.nodes(), &template_args, depth + 1, size);
+                size.set(size.get() + output.len());
+                output
+            }
+        }
+    }
+
+    /// Expand the parts again joined by `|`, e.g. the default of `{{{1|a|b}}}` is `a|b`
+    fn expand_parts(
+        &self,
+        parts: &[Vec<Node>],
+        args: &Args,
+        depth: usize,
+        size: &Cell<usize>,
+    ) -> String {
+        parts
+            .iter()
+            .map(|part| self.expand_nodes(part, args, depth, size))
+            .collect::<Vec<_>>()
+            .join("|")
+    }
+
+    /// Evaluate `{{#if: ...}}` and friends, or `None` if `name` is not a parser function.
+    /// `parts` are the arguments after the first `|`. Branches are only expanded when taken.
+    fn parser_function(
+        &self,
+        name: &str,
+        parts: &[Vec<Node>],
+        args: &Args,
+        depth: usize,
+        size: &Cell<usize>,
+    ) -> Option<String> {
+        let expand = |part: Option<&Vec<Node>>| match part {
+            Some(part) => self
+                .expand_nodes(part, args, depth, size)
+                .trim()
+                .to_string(),
+            None => String::new(),
+        };
+
+        match name {
+            "!" => return Some("|".to_string()),
+            "=" => return Some("=".to_string()),
+            "PAGENAME" | "FULLPAGENAME" | "NAMESPACE" => return Some(String::new()),
+            _ => {}
+        }
+
+        let (function, first) = name.split_once(':')?;
+        let function = function.trim();
+        let first = first.trim();
+        let output = match function.to_lowercase().as_str() {
+            "#if" => {
+                if !first.is_empty() {
+                    expand(parts.first())
+                } else {
+                    expand(parts.get(1))
+                }
+            }
+            "#ifeq" => {
+                let other = expand(parts.first());
+                if values_equal(first, &other) {
+                    expand(parts.get(1))
+                } else {
+                    expand(parts.get(2))
+                }
+            }
+            "#iferror" => {
+                if first.contains("class=\"error\"") {
+                    expand(parts.first())
+                } else if parts.len() > 1 {
+                    expand(parts.get(1))
+                } else {
+                    first.to_string()
+                }
+            }
+            "#ifexpr" => match eval_expr(first) {
+                Ok(value) if value != 0.0 => expand(parts.first()),
+                Ok(_) => expand(parts.get(1)),
+                Err(_) => String::new(),
+            },
+            "#expr" => eval_expr(first).map(format_number).unwrap_or_default(),
+            "#switch" => self.switch(first, parts, args, depth, size),
+            "lc" => first.to_lowercase(),
+            "uc" => first.to_uppercase(),
+            "lcfirst" => change_first(first, |c| c.to_lowercase().collect()),
+            "ucfirst" => change_first(first, |c| c.to_uppercase().collect()),
+            // Lua modules and tag hooks are not supported
+            "#invoke" | "#tag" | "#property" | "#statements" => String::new(),
+            function if function.starts_with('#') => String::new(),
+            // Not a parser function but a template such as `{{Template:Foo}}`
+            _ => return None,
+        };
+        Some(output)
+    }
+
+    fn switch(
+        &self,
+        value: &str,
+        parts: &[Vec<Node>],
+        args: &Args,
+        depth: usize,
+        size: &Cell<usize>,
+    ) -> String {
+        let mut matched = false;
+        let mut default = None;
+        for (i, part) in parts.iter().enumerate() {
+            match split_named_arg(part) {
+                Some((cases, result)) => {
+                    let case = self.expand_nodes(&cases, args, depth, size);
+                    let case = case.trim();
+                    if matched || values_equal(case, value) {
+                        return self
+                            .expand_nodes(&result, args, depth, size)
+                            .trim()
+                            .to_string();
+                    }
+                    if case == "#default" {
+                        default = Some(result);
+                    }
+                }
+                None => {
+                    let case = self.expand_nodes(part, args, depth, size);
+                    let case = case.trim();
+                    if i == parts.len() - 1 {
+                        // The last value without `=` is the default
+                        return case.to_string();
+                    }
+                    // Fall through to the next case with a result
+                    if values_equal(case, value) {
+                        matched = true;
+                    }
+                }
+            }
+        }
+        match default {
+            Some(result) => self
+                .expand_nodes(&result, args, depth, size)
+                .trim()
+                .to_string(),
+            None => String::new(),
+        }
+    }
+}
+
+type Args = HashMap<String, String>;
+
+/// The body of a template prepared for transclusion, parsed the first time it is used
+struct Template {
+    body: String,
+    nodes: OnceLock<Vec<Node>>,
+}
+
+impl Template {
+    fn new(text: &str) -> Template {
+        Template {
+            body: for_transclusion(text),
+            nodes: OnceLock::new(),
+        }
+    }
+
+    fn nodes(&self) -> &[Node] {
+        self.nodes.get_or_init(|| parse(&self.body))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    /// `{{name|arg|...}}`, each part is the content between `|`s
+    Template(Vec<Vec<Node>>),
+    /// `{{{name|default}}}`
+    Param(Vec<Vec<Node>>),
+}
+
+/// A `{{...}}` or `{{{...}}}` whose closing braces have not been read yet, or the whole text
+struct Frame {
+    /// Index of the opening braces
+    start: usize,
+    /// `}}` or `}}}`, `None` for the whole text
+    close: Option<&'static str>,
+    /// Finished parts, split on `|`
+    parts: Vec<Vec<Node>>,
+    nodes: Vec<Node>,
+    text: String,
+    link_depth: usize,
+    /// Braces read as text because they were nested too deeply
+    capped: Vec<usize>,
+}
+
+impl Frame {
+    fn new(start: usize, close: Option<&'static str>) -> Frame {
+        Frame {
+            start,
+            close,
+            parts: vec![],
+            nodes: vec![],
+            text: String::new(),
+            link_depth: 0,
+            capped: vec![],
+        }
+    }
+}
+
+/// Parse templates and parameters in one pass with a stack of open calls, like MediaWiki's
+/// preprocessor. Braces that are never closed are plain text.
+fn parse(text: &str) -> Vec<Node> {
+    // Opening braces and closing braces of the calls found to run to the end of the input
+    let mut unclosed: HashSet<(usize, &str)> = HashSet::new();
+    let mut stack = vec![Frame::new(0, None)];
+    let mut pos = 0;
+    loop {
+        if pos >= text.len() {
+            if stack.len() == 1 {
+                let mut frame = stack.pop().unwrap();
+                push_text(&mut frame.nodes, &mut frame.text);
+                return frame.nodes;
+            }
+            // None of the open calls is closed, read on from the outermost one as text.
+            // Braces inside them that were too deep are not closed either.
+            for frame in &stack[1..] {
+                unclosed.insert((frame.start, frame.close.unwrap()));
+                for &start in &frame.capped {
+                    unclosed.extend([(start, "}}}"), (start, "}}")]);
+                }
+            }
+            pos = stack[1].start;
+            stack.truncate(1);
+            continue;
+        }
+        let rest = &text[pos..];
+        let depth = stack.len();
+        let frame = stack.last_mut().unwrap();
+        if let Some(close) = frame.close.filter(|close| rest.starts_with(*close)) {
+            pos += close.len();
+            let mut frame = stack.pop().unwrap();
+            push_text(&mut frame.nodes, &mut frame.text);
+            frame.parts.push(frame.nodes);
+            let node = match close {
+                "}}}" => Node::Param(frame.parts),
+                _ => Node::Template(frame.parts),
+            };
+            stack.last_mut().unwrap().nodes.push(node);
+            continue;
+        }
+        if rest.starts_with("{{") {
+            // Try `{{{param}}}` first, then `{{template}}`
+            let close = [("{{{", "}}}"), ("{{", "}}")]
+                .into_iter()
+                .find(|(open, close)| rest.starts_with(open) && !unclosed.contains(&(pos, *close)))
+                .map(|(_, close)| close);
+            match close {
+                Some(close) if depth <= MAX_NESTING => {
+                    push_text(&mut frame.nodes, &mut frame.text);
+                    stack.push(Frame::new(pos, Some(close)));
+                    pos += close.len();
+                }
+                Some(_) => {
+                    frame.capped.push(pos);
+                    frame.text.push_str("{{");
+                    pos += 2;
+                }
+                None => {
+                    frame.text.push_str("{{");
+                    pos += 2;
+                }
+            }
+            continue;
+        }
+        if rest.starts_with("[[") {
+            frame.link_depth += 1;
+        } else if rest.starts_with("]]") && frame.link_depth > 0 {
+            frame.link_depth -= 1;
+        }
+        let c = rest.chars().next().unwrap();
+        if c == '|' && frame.close.is_some() && frame.link_depth == 0 {
+            push_text(&mut frame.nodes, &mut frame.text);
+            frame.parts.push(std::mem::take(&mut frame.nodes));
+        } else {
+            frame.text.push(c);
+        }
+        pos += c.len_utf8();
+    }
+}
+
+fn push_text(nodes: &mut Vec<Node>, text: &mut String) {
+    if !text.is_empty() {
+        nodes.push(Node::Text(std::mem::take(text)));
+    }
+}
+
+/// Split `key=value` at the first `=` that comes before any nested template or parameter
+fn split_named_arg(part: &[Node]) -> Option<(Vec<Node>, Vec<Node>)> {
+    for (i, node) in part.iter().enumerate() {
+        match node {
+            Node::Text(text) => {
+                if let Some((key, value)) = text.split_once('=') {
+                    let mut keys = part[..i].to_vec();
+                    keys.push(Node::Text(key.to_string()));
+                    let mut values = vec![Node::Text(value.to_string())];
+                    values.extend_from_slice(&part[i + 1..]);
+                    return Some((keys, values));
+                }
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Template name without namespace, with underscores as spaces and an uppercase first letter
+fn normalize_name(name: &str) -> String {
+    let name = name.trim();
+    let name = ["Template:", "template:", "模板:", "样板:", "樣板:"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name);
+    let name = name.replace('_', " ");
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    change_first(&name, |c| c.to_uppercase().collect())
+}
+
+fn change_first(text: &str, f: impl Fn(char) -> String) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) => f(c) + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+/// Keep only what a page transcluding the template sees
+fn for_transclusion(text: &str) -> String {
+    let text = if ONLYINCLUDE.is_match(text) {
+        ONLYINCLUDE
+            .captures_iter(text)
+            .map(|caps| caps[1].to_string())
+            .collect()
+    } else {
+        text.to_string()
+    };
+    let text = NOINCLUDE.replace_all(&text, "");
+    let text = INCLUDEONLY_TAG.replace_all(&text, "");
+    COMMENT.replace_all(&text, "").to_string()
+}
+
+/// Replace `<nowiki>`, `<pre>` and extension tags with markers so that templates in them are not
+/// expanded. Returns the text and the tags to put back with `unstrip`.
+fn strip_tags(text: &str) -> (String, Vec<String>) {
+    let mut stripped = vec![];
+    let text = PAIRED_PROTECTED_TAG.replace_all(text, |caps: &regex::Captures| {
+        stripped.push(caps[0].to_string());
+        format!("\u{7f}UNIQ-{}-QINU\u{7f}", stripped.len() - 1)
+    });
+    (text.to_string(), stripped)
+}
+
+/// Put the tags removed by `strip_tags` back
+fn unstrip(text: &str, stripped: &[String]) -> String {
+    STRIP_MARKER
+        .replace_all(text, |caps: &regex::Captures| {
+            let i: usize = caps[1].parse().unwrap();
+            stripped[i].clone()
+        })
+        .to_string()
+}
+
+/// Keep only what a reader of the page itself sees
+fn for_page_view(text: &str) -> String {
+    let text = INCLUDEONLY.replace_all(text, "");
+    let text = INCLUSION_TAG.replace_all(&text, "");
+    COMMENT.replace_all(&text, "").to_string()
+}
+
+/// Compare as numbers if both sides are numbers, otherwise as strings
+fn values_equal(a: &str, b: &str) -> bool {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Format like PHP does with 14 significant digits, without a fraction for whole numbers
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        return format!("{}", value as i64);
+    }
+    let rounded: f64 = format!("{value:.13e}").parse().unwrap_or(value);
+    format!("{rounded}")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+/// Operators of `#expr` from lowest to highest precedence
+const BINARY_OPS: &[&[&str]] = &[
+    &["or"],
+    &["and"],
+    &["=", "!=", "<>", "<=", ">=", "<", ">"],
+    &["round"],
+    &["+", "-"],
+    &["*", "/", "div", "mod", "fmod"],
+    &["^"],
+];
+
+const UNARY_OPS: &[&str] = &[
+    "-", "+", "not", "abs", "floor", "ceil", "trunc", "ln", "exp", "sqrt", "sin", "cos", "tan",
+];
+
+/// Evaluate the arithmetic of `{{#expr: ...}}`
+fn eval_expr(expr: &str) -> Result<f64, String> {
+    let tokens = tokenize(expr)?;
+    if tokens.is_empty() {
+        return Ok(0.0);
+    }
+    let mut pos = 0;
+    let value = parse_binary(&tokens, &mut pos, 0)?;
+    if pos < tokens.len() {
+        return Err(format!("unexpected {:?}", tokens[pos]));
+    }
+    Ok(value)
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let words = [
+        "fmod", "mod", "div", "round", "and", "or", "not", "abs", "floor", "ceil", "trunc", "ln",
+        "exp", "sqrt", "sin", "cos", "tan",
+    ];
+    let symbols = [
+        "!=", "<>", "<=", ">=", "=", "<", ">", "+", "-", "*", "/", "^",
+    ];
+    let mut tokens = vec![];
+    let expr = expr.replace('−', "-").replace('×', "*").replace('÷', "/");
+    let mut rest = expr.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_digit() || c == '.' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let mut number: f64 = rest[..len]
+                .parse()
+                .map_err(|_| format!("invalid number {}", &rest[..len]))?;
+            rest = &rest[len..];
+            // Scientific notation such as `1.5e3`
+            if let Some(exponent) = rest.strip_prefix('e').or_else(|| rest.strip_prefix('E')) {
+                let len = exponent
+                    .find(|c: char| !(c.is_ascii_digit() || c == '-' || c == '+'))
+                    .unwrap_or(exponent.len());
+                if let Ok(exponent) = exponent[..len].parse::<i32>() {
+                    number *= 10f64.powi(exponent);
+                    rest = &rest[1 + len..];
+                }
+            }
+            tokens.push(Token::Number(number));
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            rest = &rest[1..];
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            rest = &rest[1..];
+        } else if let Some(symbol) = symbols.iter().find(|s| rest.starts_with(**s)) {
+            tokens.push(Token::Op(symbol));
+            rest = &rest[symbol.len()..];
+        } else if let Some(word) = words.iter().find(|w| starts_with_word(rest, w)) {
+            tokens.push(Token::Op(word));
+            rest = &rest[word.len()..];
+        } else if starts_with_word(rest, "pi") {
+            tokens.push(Token::Number(std::f64::consts::PI));
+            rest = &rest[2..];
+        } else if rest.starts_with('e') {
+            tokens.push(Token::Number(std::f64::consts::E));
+            rest = &rest[1..];
+        } else {
+            return Err(format!("unrecognized punctuation {c}"));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn starts_with_word(text: &str, word: &str) -> bool {
+    text.get(..word.len())
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(word))
+}
+
+fn parse_binary(tokens: &[Token], pos: &mut usize, level: usize) -> Result<f64, String> {
+    if level == BINARY_OPS.len() {
+        return parse_unary(tokens, pos);
+    }
+    let mut left = parse_binary(tokens, pos, level + 1)?;
+    while let Some(Token::Op(op)) = tokens.get(*pos) {
+        if !BINARY_OPS[level].contains(op) {
+            break;
+        }
+        *pos += 1;
+        let right = parse_binary(tokens, pos, level + 1)?;
+        left = apply_binary(op, left, right)?;
+    }
+    Ok(left)
+}
+
+fn parse_unary(tokens: &[Token], pos: &mut usize) -> Result<f64, String> {
+    match tokens.get(*pos) {
+        Some(Token::Op(op)) if UNARY_OPS.contains(op) => {
+            *pos += 1;
+            let value = parse_unary(tokens, pos)?;
+            Ok(match *op {
+                "-" => -value,
+                "+" => value,
+                "not" => (value == 0.0) as i32 as f64,
+                "abs" => value.abs(),
+                "floor" => value.floor(),
+                "ceil" => value.ceil(),
+                "trunc" => value.trunc(),
+                "ln" => value.ln(),
+                "exp" => value.exp(),
+                "sqrt" => value.sqrt(),
+                "sin" => value.sin(),
+                "cos" => value.cos(),
+                _ => value.tan(),
+            })
+        }
+        Some(Token::Number(value)) => {
+            *pos += 1;
+            Ok(*value)
+        }
+        Some(Token::Open) => {
+            *pos += 1;
+            let value = parse_binary(tokens, pos, 0)?;
+            match tokens.get(*pos) {
+                Some(Token::Close) => {
+                    *pos += 1;
+                    Ok(value)
+                }
+                _ => Err("missing closing parenthesis".to_string()),
+            }
+        }
+        token => Err(format!("unexpected {token:?}")),
+    }
+}
+
+fn apply_binary(op: &str, left: f64, right: f64) -> Result<f64, String> {
+    let bool_value = |b: bool| b as i32 as f64;
+    Ok(match op {
+        "or" => bool_value(left != 0.0 || right != 0.0),
+        "and" => bool_value(left != 0.0 && right != 0.0),
+        "=" => bool_value(left == right),
+        "!=" | "<>" => bool_value(left != right),
+        "<" => bool_value(left < right),
+        ">" => bool_value(left > right),
+        "<=" => bool_value(left <= right),
+        ">=" => bool_value(left >= right),
+        "round" => {
+            let factor = 10f64.powi(right as i32);
+            (left * factor).round() / factor
+        }
+        "+" => left + right,
+        "-" => left - right,
+        "*" => left * right,
+        "/" | "div" if right == 0.0 => return Err("division by zero".to_string()),
+        "/" | "div" => left / right,
+        "mod" if right as i64 == 0 => return Err("division by zero".to_string()),
+        "mod" => ((left as i64) % (right as i64)) as f64,
+        "fmod" => left % right,
+        _ => left.powf(right),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates(pages: &[(&str, &str)]) -> Templates {
+        Templates {
+            bodies: pages
+                .iter()
+                .map(|(name, body)| (normalize_name(name), Template::new(body)))
+                .collect(),
+            redirects: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn expands_parameters_and_defaults() {
+        let templates = templates(&[("Greet", "Hello {{{1}}} {{{name|nobody}}}")]);
+        assert_eq!(templates.expand("{{greet|a}}"), "Hello a nobody");
+        assert_eq!(templates.expand("{{Greet|a|name=b}}"), "Hello a b");
+    }
+
+    #[test]
+    fn templates_are_parsed_once_used() {
+        let templates = templates(&[("Outer", "[{{inner}}]"), ("Inner", "x")]);
+        assert!(templates.get("Inner").unwrap().nodes.get().is_none());
+        assert_eq!(templates.expand("{{outer}}{{outer}}"), "[x][x]");
+        assert!(templates.get("Inner").unwrap().nodes.get().is_some());
+    }
+
+    #[test]
+    fn stray_braces_are_text() {
+        let templates = templates(&[]);
+        let text = "{{a ".repeat(20000);
+        assert_eq!(templates.expand(&text), text);
+        assert_eq!(templates.expand("a }} b {{ c"), "a }} b {{ c");
+    }
+
+    #[test]
+    fn expanded_size_is_capped() {
+        // Each level doubles the output, 2^30 copies without a cap
+        let mut pages: Vec<(String, String)> = (0..30)
+            .map(|i| {
+                (
+                    format!("T{i}"),
+                    format!("{{{{T{}}}}}{{{{T{}}}}}", i + 1, i + 1),
+                )
+            })
+            .collect();
+        pages.push(("T30".to_string(), "x".repeat(100)));
+        let pages: Vec<(&str, &str)> = pages
+            .iter()
+            .map(|(name, body)| (name.as_str(), body.as_str()))
+            .collect();
+        let expanded = templates(&pages).expand("{{T0}}");
+        assert!(
+            expanded.len() <= 3 * MAX_EXPANDED_SIZE,
+            "{}",
+            expanded.len()
+        );
+    }
+
+    #[test]
+    fn protected_tags_are_not_expanded() {
+        let templates = templates(&[("Foo", "bar")]);
+        assert_eq!(
+            templates.expand("{{foo}}<nowiki>{{foo}}</nowiki>"),
+            "bar<nowiki>{{foo}}</nowiki>"
+        );
+        assert_eq!(
+            templates.expand("<math>{{foo}}</math><ref name=\"a\">{{foo}}</ref>"),
+            "<math>{{foo}}</math><ref name=\"a\">{{foo}}</ref>"
+        );
+    }
+
+    #[test]
+    fn switch() {
+        let templates = templates(&[]);
+        let expand = |text: &str| templates.expand(text);
+        assert_eq!(expand("{{#switch: b | a = 1 | b = 2 | #default = 3}}"), "2");
+        assert_eq!(expand("{{#switch: z | a = 1 | #default = 3}}"), "3");
+        // Cases without a result fall through to the next one
+        assert_eq!(expand("{{#switch: a | a | b = 2 | c = 3}}"), "2");
+        // The last value without `=` is the default
+        assert_eq!(expand("{{#switch: z | a = 1 | 9}}"), "9");
+        assert_eq!(expand("{{#switch: 1.0 | 1 = one}}"), "one");
+        assert_eq!(expand("{{#switch: z | a = 1}}"), "");
+    }
+
+    #[test]
+    fn expr() {
+        assert_eq!(eval_expr("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(eval_expr("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(eval_expr("2 ^ 10"), Ok(1024.0));
+        assert_eq!(eval_expr("7 mod 3"), Ok(1.0));
+        assert_eq!(eval_expr("-3 + abs -4"), Ok(1.0));
+        assert_eq!(eval_expr("1.23456 round 2"), Ok(1.23));
+        assert_eq!(eval_expr("1 < 2 and not 0"), Ok(1.0));
+        assert_eq!(eval_expr("1.5e3"), Ok(1500.0));
+        assert_eq!(eval_expr(""), Ok(0.0));
+        assert!(eval_expr("1 / 0").is_err());
+        assert!(eval_expr("(1 + 2").is_err());
+        assert!(eval_expr("1 +* 2").is_err());
+        assert_eq!(format_number(1.0 / 3.0), "0.33333333333333");
+        assert_eq!(format_number(1e3), "1000");
+    }
+}
@@ -0,0 +1,199 @@
+// Text being cleaned up, which can remember where each byte came from in the text it started as
+use regex::Regex;
+use std::ops::Range;
+
+/// A string changed by replacements, optionally with the offset in the original text of each byte
+pub struct TrackedText {
+    pub text: String,
+    /// Byte offset in the original text of each byte of `text`, and of its end
+    origins: Option<Vec<usize>>,
+}
+
+/// A match to replace and the range of its first group
+type Replaced = (Range<usize>, Option<Range<usize>>);
+
+/// Part of a replaced text: bytes of the old text, or inserted text that comes from an offset
+/// of the old text
+enum Piece<'a> {
+    Copy(Range<usize>),
+    Insert(&'a str, usize),
+}
+
+impl TrackedText {
+    /// Text to clean up, recording the origin of each byte if `track` is set
+    pub fn new(text: &str, track: bool) -> Self {
+        TrackedText {
+            text: text.to_string(),
+            origins: track.then(|| (0..=text.len()).collect()),
+        }
+    }
+
+    /// Replace every match of `regex`. `$1` in `replacement` is the first group of the match.
+    pub fn replace_all(&mut self, regex: &Regex, replacement: &str) {
+        let matches: Vec<Replaced> = regex
+            .captures_iter(&self.text)
+            .map(|caps| (caps.get(0).unwrap().range(), caps.get(1).map(|m| m.range())))
+            .collect();
+        self.apply(matches, replacement);
+    }
+
+    /// Like `replace_all` for regexes with backreferences, stopping at the first match that fails
+    pub fn replace_all_fancy(&mut self, regex: &fancy_regex::Regex, replacement: &str) {
+        let matches: Vec<Replaced> = regex
+            .captures_iter(&self.text)
+            .map_while(Result::ok)
+            .map(|caps| {
+                let whole = caps.get(0).unwrap();
+                (
+                    whole.start()..whole.end(),
+                    caps.get(1).map(|m| m.start()..m.end()),
+                )
+            })
+            .collect();
+        self.apply(matches, replacement);
+    }
+
+    /// Replace every occurrence of `from` with `to`
+    pub fn replace(&mut self, from: &str, to: &str) {
+        let matches: Vec<Replaced> = self
+            .text
+            .match_indices(from)
+            .map(|(i, _)| (i..i + from.len(), None))
+            .collect();
+        self.apply(matches, to);
+    }
+
+    /// Trim every line, and keep the ones for which `keep` holds
+    pub fn retain_lines(&mut self, keep: impl Fn(&str) -> bool) {
+        let mut text = String::with_capacity(self.text.len());
+        let mut origins = self.origins.as_ref().map(|_| vec![]);
+        let mut start = 0;
+        for line in self.text.lines() {
+            let line_start = start + line.len() - line.trim_start().len();
+            start += self.text[start..].find('\n').map_or(line.len(), |i| i + 1);
+            let line = line.trim();
+            if !keep(line) {
+                continue;
+            }
+            let range = line_start..line_start + line.len();
+            if !text.is_empty() {
+                text.push('\n');
+                if let (Some(origins), Some(old)) = (&mut origins, &self.origins) {
+                    origins.push(old[range.start]);
+                }
+            }
+            text.push_str(line);
+            if let (Some(origins), Some(old)) = (&mut origins, &self.origins) {
+                origins.extend_from_slice(&old[range]);
+            }
+        }
+        self.finish(text, origins);
+    }
+
+    /// Remove leading and trailing whitespace
+    pub fn trim(&mut self) {
+        let start = self.text.len() - self.text.trim_start().len();
+        let end = self.text.trim_end().len().max(start);
+        self.text = self.text[start..end].to_string();
+        if let Some(origins) = &mut self.origins {
+            let end_origin = origins[end];
+            origins.truncate(end);
+            origins.drain(..start);
+            origins.push(end_origin);
+        }
+    }
+
+    /// Bytes of the text that come from `range` of the original text, without surrounding
+    /// whitespace. `None` if the origins are not tracked or nothing of `range` is left.
+    pub fn find_origin(&self, range: Range<usize>) -> Option<Range<usize>> {
+        let origins = &self.origins.as_ref()?[..self.text.len()];
+        let start = origins.partition_point(|&origin| origin < range.start);
+        let end = origins.partition_point(|&origin| origin < range.end);
+        let text = self.text.get(start..end)?;
+        let start = start + text.len() - text.trim_start().len();
+        let end = start + text.trim().len();
+        (start < end).then_some(start..end)
+    }
+
+    /// Replace `matches`, in order and not overlapping, with `replacement`. The bytes of the group
+    /// keep their origin, and inserted text comes from the start of the match or the end of the
+    /// group before it, so origins stay in order.
+    fn apply(&mut self, matches: Vec<Replaced>, replacement: &str) {
+        if matches.is_empty() {
+            return;
+        }
+        let (before, after) = match replacement.split_once("$1") {
+            Some((before, after)) => (before, Some(after)),
+            None => (replacement, None),
+        };
+        let mut pieces = vec![];
+        let mut last = 0;
+        for (whole, group) in matches {
+            pieces.push(Piece::Copy(last..whole.start));
+            pieces.push(Piece::Insert(before, whole.start));
+            if let Some(after) = after {
+                let group = group.unwrap_or(whole.end..whole.end);
+                pieces.push(Piece::Copy(group.clone()));
+                pieces.push(Piece::Insert(after, group.end));
+            }
+            last = whole.end;
+        }
+        pieces.push(Piece::Copy(last..self.text.len()));
+
+        let mut text = String::with_capacity(self.text.len());
+        let mut origins = self.origins.as_ref().map(|_| vec![]);
+        for piece in pieces {
+            match piece {
+                Piece::Copy(range) => {
+                    text.push_str(&self.text[range.clone()]);
+                    if let (Some(origins), Some(old)) = (&mut origins, &self.origins) {
+                        origins.extend_from_slice(&old[range]);
+                    }
+                }
+                Piece::Insert(inserted, at) => {
+                    text.push_str(inserted);
+                    if let (Some(origins), Some(old)) = (&mut origins, &self.origins) {
+                        origins.extend(std::iter::repeat_n(old[at], inserted.len()));
+                    }
+                }
+            }
+        }
+        self.finish(text, origins);
+    }
+
+    fn finish(&mut self, text: String, origins: Option<Vec<usize>>) {
+        if let (Some(mut origins), Some(old)) = (origins, &self.origins) {
+            // The end of the text comes from the end of the text it was made from
+            origins.push(old[self.text.len()]);
+            self.origins = Some(origins);
+        }
+        self.text = text;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins_follow_replacements() {
+        let mut text = TrackedText::new("  a (b)  c...\n", true);
+        text.replace_all(&Regex::new(r"\((\w)\)").unwrap(), "[$1]");
+        text.replace_all(&Regex::new(r" +").unwrap(), " ");
+        text.replace("...", "…");
+        text.trim();
+        assert_eq!(text.text, "a [b] c…");
+        assert_eq!(text.find_origin(5..6), Some(3..4));
+        assert_eq!(text.find_origin(4..7), Some(2..5));
+        assert_eq!(text.find_origin(10..13), Some(7..10));
+        assert_eq!(text.find_origin(7..9), None);
+    }
+
+    #[test]
+    fn untracked_text_has_no_origins() {
+        let mut text = TrackedText::new("a\n b \nc", false);
+        text.retain_lines(|line| line != "c");
+        assert_eq!(text.text, "a\nb");
+        assert_eq!(text.find_origin(0..1), None);
+    }
+}
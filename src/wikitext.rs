@@ -0,0 +1,777 @@
+// Renders wikitext into the same kind of HTML that MediaWiki's `action=parse` returns,
+// so that `html_to_text` works the same for both backends.
+// Loosely follows https://github.com/attardi/wikiextractor/blob/8f1b434a80608e1e313d38d263ed7c79c9ee75a9/wikiextractor/extract.py
+use itertools::Itertools;
+use regex::Regex;
+use std::sync::LazyLock;
+
+use crate::backend::Backend;
+use crate::mediawiki::ParseError;
+use crate::template::Templates;
+use crate::zhconv::Converter;
+use crate::ZhVariant;
+
+/// Backend that renders wikitext in-process without a MediaWiki server.
+/// Templates are expanded if they are loaded, otherwise they are removed.
+/// Text is only converted between variants by `-{...}-` markup if no conversion tables are loaded.
+pub struct NativeBackend {
+    templates: Option<Templates>,
+    converter: Converter,
+}
+
+impl NativeBackend {
+    pub fn new(templates: Option<Templates>, converter: Converter) -> Self {
+        NativeBackend {
+            templates,
+            converter,
+        }
+    }
+
+    fn expand(&self, wikitext: &str) -> String {
+        match &self.templates {
+            Some(templates) => templates.expand(wikitext),
+            None => wikitext.to_string(),
+        }
+    }
+}
+
+impl Backend for NativeBackend {
+    fn render(&self, wikitext: &str, variant: ZhVariant) -> Result<String, ParseError> {
+        let converted = self.converter.convert(&self.expand(wikitext), variant);
+        Ok(wikitext_to_html(&converted))
+    }
+
+    /// Templates are only expanded once for all variants
+    fn render_variants(
+        &self,
+        wikitext: &str,
+        variants: &[ZhVariant],
+    ) -> Vec<Result<String, ParseError>> {
+        let expanded = self.expand(wikitext);
+        variants
+            .iter()
+            .map(|&variant| {
+                Ok(wikitext_to_html(
+                    &self.converter.convert(&expanded, variant),
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Namespaces whose links are not rendered as text, e.g. images and categories
+const HIDDEN_LINK_NAMESPACES: &[&str] = &[
+    "file", "image", "media", "文件", "檔案", "档案", "图像", "圖像", "图片", "圖片", "category",
+    "分类", "分類",
+];
+
+/// Tags that are dropped together with their content
+pub const DROPPED_TAGS: &[&str] = &[
+    "ref",
+    "references",
+    "gallery",
+    "timeline",
+    "math",
+    "chem",
+    "score",
+    "graph",
+    "imagemap",
+    "mapframe",
+    "maplink",
+    "templatedata",
+    "templatestyles",
+    "syntaxhighlight",
+    "source",
+];
+
+// Patterns used for every page, compiled once
+static MAGIC_WORD: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"__[A-Z]+__").unwrap());
+/// An HTML comment, up to the end of the text if it is not closed
+pub static COMMENT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)<!--.*?(-->|$)").unwrap());
+static PAIRED_DROPPED_TAG: LazyLock<Regex> = LazyLock::new(|| {
+    let tags = DROPPED_TAGS.join("|");
+    Regex::new(&format!(r"(?is)<({tags})(\s[^>]*)?>.*?</({tags})\s*>")).unwrap()
+});
+static SELF_CLOSING_DROPPED_TAG: LazyLock<Regex> = LazyLock::new(|| {
+    let tags = DROPPED_TAGS.join("|");
+    Regex::new(&format!(r"(?i)<({tags})(\s[^>]*)?/>")).unwrap()
+});
+static CELL_SPAN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)\b(rowspan|colspan)\s*=\s*["']?(\d+)"#).unwrap());
+static HTML_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^</?[A-Za-z][A-Za-z0-9]*(\s[^<>]*)?/?>").unwrap());
+static ENTITY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^&(#[0-9]+|#[xX][0-9a-fA-F]+|[A-Za-z]+);").unwrap());
+static EXTERNAL_LINK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\[((?:https?:|//)[^\s\]]+)(?: ([^\]]*))?\]").unwrap());
+
+pub fn wikitext_to_html(wikitext: &str) -> String {
+    let text = remove_comments(wikitext);
+    let text = remove_dropped_tags(&text);
+    let text = remove_templates(&text);
+    let text = MAGIC_WORD.replace_all(&text, "");
+    render_blocks(&text.lines().collect::<Vec<_>>())
+}
+
+fn remove_comments(text: &str) -> String {
+    COMMENT.replace_all(text, "").to_string()
+}
+
+fn remove_dropped_tags(text: &str) -> String {
+    let text = PAIRED_DROPPED_TAG.replace_all(text, "");
+    SELF_CLOSING_DROPPED_TAG.replace_all(&text, "").to_string()
+}
+
+/// Remove `{{...}}` template calls and parser functions, including nested ones
+pub fn remove_templates(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut depth = 0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        if rest.starts_with("{{") {
+            depth += 1;
+            rest = &rest[2..];
+        } else if depth > 0 && rest.starts_with("}}") {
+            depth -= 1;
+            rest = &rest[2..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            if depth == 0 {
+                output.push(c);
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    output
+}
+
+fn render_blocks(lines: &[&str]) -> String {
+    let mut html = String::new();
+    let mut paragraph: Vec<&str> = vec![];
+    let mut list = ListState::default();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim();
+
+        let is_paragraph_line = !trimmed.is_empty()
+            && !trimmed.starts_with("{|")
+            && !line.starts_with(['*', '#', ';', ':', ' '])
+            && heading(trimmed).is_none()
+            && !trimmed.starts_with("----");
+        if !is_paragraph_line {
+            flush_paragraph(&mut html, &mut paragraph);
+        }
+        if !line.starts_with(['*', '#', ';', ':']) {
+            list.close_all(&mut html);
+        }
+
+        if trimmed.starts_with("{|") {
+            let end = table_end(lines, i);
+            render_table(&mut html, &lines[i..end]);
+            i = end;
+            continue;
+        } else if let Some((level, title)) = heading(trimmed) {
+            html.push_str(&format!("<h{level}>{}</h{level}>", render_inline(title)));
+        } else if line.starts_with(['*', '#', ';', ':']) {
+            list.push_line(&mut html, line);
+        } else if trimmed.starts_with("----") {
+            html.push_str("<hr/>");
+        } else if line.starts_with(' ') && !trimmed.is_empty() {
+            html.push_str(&format!("<pre>{}</pre>", escape(trimmed)));
+        } else if !trimmed.is_empty() {
+            paragraph.push(trimmed);
+        }
+        i += 1;
+    }
+    flush_paragraph(&mut html, &mut paragraph);
+    list.close_all(&mut html);
+    html
+}
+
+fn flush_paragraph(html: &mut String, paragraph: &mut Vec<&str>) {
+    if paragraph.is_empty() {
+        return;
+    }
+    let text = paragraph.iter().map(|line| render_inline(line)).join("\n");
+    if !text.trim().is_empty() {
+        html.push_str(&format!("<p>{text}\n</p>"));
+    }
+    paragraph.clear();
+}
+
+/// Level and text of a `== heading ==` line
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let start = line.chars().take_while(|&c| c == '=').count();
+    let end = line.chars().rev().take_while(|&c| c == '=').count();
+    if start == 0 || end == 0 || start + end >= line.len() {
+        return None;
+    }
+    let level = start.min(end).min(6);
+    let title = line[level..line.len() - level].trim();
+    if level < 2 || title.is_empty() {
+        return None;
+    }
+    Some((level, title))
+}
+
+/// Open lists of the previous line, one entry per prefix character such as `*` or `#`
+#[derive(Default)]
+struct ListState {
+    open: Vec<char>,
+}
+
+impl ListState {
+    fn push_line(&mut self, html: &mut String, line: &str) {
+        let prefix: Vec<char> = line
+            .chars()
+            .take_while(|c| matches!(c, '*' | '#' | ';' | ':'))
+            .collect();
+        let content = line[prefix.len()..].trim();
+
+        let common = self
+            .open
+            .iter()
+            .zip(&prefix)
+            .take_while(|(a, b)| list_tag(**a) == list_tag(**b))
+            .count();
+        while self.open.len() > common {
+            let c = self.open.pop().unwrap();
+            html.push_str(&format!("</{}></{}>", item_tag(c), list_tag(c)));
+        }
+        if common == prefix.len() && common > 0 {
+            // Another item of the innermost list
+            let c = self.open.pop().unwrap();
+            html.push_str(&format!("</{}>", item_tag(c)));
+            let c = prefix[common - 1];
+            html.push_str(&format!("<{}>", item_tag(c)));
+            self.open.push(c);
+        }
+        for &c in &prefix[common..] {
+            html.push_str(&format!("<{}><{}>", list_tag(c), item_tag(c)));
+            self.open.push(c);
+        }
+
+        // `; term : definition` on a single line
+        if prefix.last() == Some(&';') {
+            if let Some(colon) = find_outside_links(content, ':') {
+                html.push_str(&render_inline(content[..colon].trim()));
+                html.push_str("</dt><dd>");
+                html.push_str(&render_inline(content[colon + 1..].trim()));
+                self.open.pop();
+                self.open.push(':');
+                return;
+            }
+        }
+        html.push_str(&render_inline(content));
+    }
+
+    fn close_all(&mut self, html: &mut String) {
+        while let Some(c) = self.open.pop() {
+            html.push_str(&format!("</{}></{}>", item_tag(c), list_tag(c)));
+        }
+    }
+}
+
+fn list_tag(c: char) -> &'static str {
+    match c {
+        '*' => "ul",
+        '#' => "ol",
+        _ => "dl",
+    }
+}
+
+fn item_tag(c: char) -> &'static str {
+    match c {
+        ';' => "dt",
+        ':' => "dd",
+        _ => "li",
+    }
+}
+
+/// Byte index of the first `c` that is not inside a `[[...]]` link
+fn find_outside_links(text: &str, c: char) -> Option<usize> {
+    let mut depth = 0;
+    for (i, ch) in text.char_indices() {
+        match ch {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            _ if ch == c && depth <= 0 => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Index one past the `|}` line closing the table that starts at `lines[start]`
+fn table_end(lines: &[&str], start: usize) -> usize {
+    let mut depth = 0;
+    for (i, line) in lines.iter().enumerate().skip(start) {
+        let line = line.trim();
+        if line.starts_with("{|") {
+            depth += 1;
+        } else if line.starts_with("|}") {
+            depth -= 1;
+            if depth == 0 {
+                return i + 1;
+            }
+        }
+    }
+    lines.len()
+}
+
+/// Render a `{| ... |}` table, keeping only the `rowspan` and `colspan` attributes of cells
+fn render_table(html: &mut String, lines: &[&str]) {
+    let mut rows: Vec<Vec<Cell>> = vec![];
+    let mut caption = None;
+    let mut i = 1;
+    while i < lines.len() {
+        let line = lines[i].trim();
+        if line.starts_with("{|") {
+            // Nested table inside the current cell
+            let end = i + table_end(&lines[i..], 0);
+            if let Some(cell) = rows.last_mut().and_then(|row| row.last_mut()) {
+                cell.nested.push_str(&{
+                    let mut nested = String::new();
+                    render_table(&mut nested, &lines[i..end]);
+                    nested
+                });
+            }
+            i = end;
+            continue;
+        }
+        if line.starts_with("|}") {
+            break;
+        } else if let Some(rest) = line.strip_prefix("|+") {
+            caption = Some(rest.trim().to_string());
+        } else if line.starts_with("|-") {
+            rows.push(vec![]);
+        } else if let Some(rest) = line.strip_prefix('!') {
+            if rows.is_empty() {
+                rows.push(vec![]);
+            }
+            let row = rows.last_mut().unwrap();
+            for cell in split_cells(rest, &["!!", "||"]) {
+                row.push(Cell::new(cell, true));
+            }
+        } else if let Some(rest) = line.strip_prefix('|') {
+            if rows.is_empty() {
+                rows.push(vec![]);
+            }
+            let row = rows.last_mut().unwrap();
+            for cell in split_cells(rest, &["||"]) {
+                row.push(Cell::new(cell, false));
+            }
+        } else if let Some(cell) = rows.last_mut().and_then(|row| row.last_mut()) {
+            // Continuation of a multi-line cell
+            cell.lines.push(lines[i].to_string());
+        }
+        i += 1;
+    }
+
+    html.push_str("<table>");
+    if let Some(caption) = caption {
+        html.push_str(&format!("<caption>{}</caption>", render_inline(&caption)));
+    }
+    for row in rows.iter().filter(|row| !row.is_empty()) {
+        html.push_str("<tr>");
+        for cell in row {
+            let tag = if cell.header { "th" } else { "td" };
+            html.push_str(&format!("<{tag}{}>", cell.attributes));
+            let lines: Vec<&str> = cell.lines.iter().map(String::as_str).collect();
+            if lines.len() == 1 {
+                html.push_str(&render_inline(lines[0].trim()));
+            } else {
+                html.push_str(&render_blocks(&lines));
+            }
+            html.push_str(&cell.nested);
+            html.push_str(&format!("</{tag}>"));
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</table>");
+}
+
+struct Cell {
+    header: bool,
+    attributes: String,
+    lines: Vec<String>,
+    nested: String,
+}
+
+impl Cell {
+    /// A cell is either `content` or `attributes | content`
+    fn new(cell: &str, header: bool) -> Cell {
+        let (attributes, content) = match find_outside_links(cell, '|') {
+            Some(i) if !cell[..i].contains("[[") => (&cell[..i], &cell[i + 1..]),
+            _ => ("", cell),
+        };
+        let attributes = CELL_SPAN
+            .captures_iter(attributes)
+            .map(|caps| format!(r#" {}="{}""#, caps[1].to_lowercase(), &caps[2]))
+            .collect();
+        Cell {
+            header,
+            attributes,
+            lines: vec![content.to_string()],
+            nested: String::new(),
+        }
+    }
+}
+
+/// Split a table line on cell separators that are not inside links
+fn split_cells<'a>(line: &'a str, separators: &[&str]) -> Vec<&'a str> {
+    let mut cells = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    let mut i = 0;
+    while i < line.len() {
+        let rest = &line[i..];
+        if rest.starts_with("[[") {
+            depth += 1;
+            i += 2;
+        } else if rest.starts_with("]]") {
+            depth -= 1;
+            i += 2;
+        } else if let Some(sep) = separators
+            .iter()
+            .find(|sep| depth <= 0 && rest.starts_with(**sep))
+        {
+            cells.push(&line[start..i]);
+            i += sep.len();
+            start = i;
+        } else {
+            i += rest.chars().next().unwrap().len_utf8();
+        }
+    }
+    cells.push(&line[start..]);
+    cells
+}
+
+/// Render links, bold, italic, HTML tags and entities of a single line
+pub fn render_inline(text: &str) -> String {
+    let mut html = String::new();
+    let mut quotes = QuoteState::default();
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        if rest.starts_with("[[") {
+            if let Some(end) = link_end(rest) {
+                html.push_str(&render_link(&rest[2..end - 2]));
+                i += end;
+                continue;
+            }
+        } else if rest.starts_with('[') {
+            if let Some(caps) = EXTERNAL_LINK.captures(rest) {
+                if let Some(label) = caps.get(2).filter(|label| !label.as_str().is_empty()) {
+                    html.push_str(&format!(
+                        r#"<a class="external text" href="{}">{}</a>"#,
+                        escape(&caps[1]),
+                        render_inline(label.as_str())
+                    ));
+                }
+                i += caps[0].len();
+                continue;
+            }
+        } else if rest.starts_with("''") {
+            let count = rest.chars().take_while(|&c| c == '\'').count();
+            quotes.push(&mut html, count);
+            i += count;
+            continue;
+        } else if rest.starts_with('<') {
+            if let Some(tag) = HTML_TAG.find(rest) {
+                i += tag.end();
+                continue;
+            }
+        } else if rest.starts_with('&') {
+            if let Some(caps) = ENTITY.captures(rest) {
+                match decode_entity(&caps[1]) {
+                    Some(c) => html.push_str(&escape(&c.to_string())),
+                    None => html.push_str(&escape(&caps[0])),
+                }
+                i += caps[0].len();
+                continue;
+            }
+        }
+        let c = rest.chars().next().unwrap();
+        html.push_str(&escape(&c.to_string()));
+        i += c.len_utf8();
+    }
+    quotes.close(&mut html);
+    html
+}
+
+/// Byte index one past the `]]` that closes the `[[` at the start of `text`
+fn link_end(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        if rest.starts_with("[[") {
+            depth += 1;
+            i += 2;
+        } else if rest.starts_with("]]") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return Some(i);
+            }
+        } else if rest.starts_with('\n') {
+            return None;
+        } else {
+            i += rest.chars().next().unwrap().len_utf8();
+        }
+    }
+    None
+}
+
+/// Render the inside of a `[[target|label]]` link
+fn render_link(inner: &str) -> String {
+    let (target, label) = match find_outside_links(inner, '|') {
+        Some(i) => (inner[..i].trim(), Some(inner[i + 1..].trim())),
+        None => (inner.trim(), None),
+    };
+    let is_colon_link = target.starts_with(':');
+    let target = target.trim_start_matches(':').trim();
+    if target.is_empty() {
+        return String::new();
+    }
+
+    if let Some((namespace, _)) = target.split_once(':') {
+        let namespace = namespace.trim().to_lowercase();
+        let is_interlanguage = namespace.len() <= 12
+            && namespace
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c == '-')
+            && !matches!(
+                namespace.as_str(),
+                "wikipedia" | "help" | "portal" | "template"
+            );
+        if !is_colon_link
+            && (HIDDEN_LINK_NAMESPACES.contains(&namespace.as_str()) || is_interlanguage)
+        {
+            return String::new();
+        }
+    }
+
+    let label = match label {
+        // Pipe trick: `[[Foo (bar)|]]` is shown as `Foo`
+        Some("") => target.split(" (").next().unwrap().to_string(),
+        Some(label) => label.to_string(),
+        None => target.to_string(),
+    };
+    let href = format!("/index.php/{}", target.replace(' ', "_"));
+    format!(
+        r#"<a href="{}" title="{}">{}</a>"#,
+        escape(&href),
+        escape(target),
+        render_inline(&label)
+    )
+}
+
+/// Bold and italic toggled by runs of apostrophes, closed at the end of the line
+#[derive(Default)]
+struct QuoteState {
+    /// Open tags, innermost last
+    open: Vec<&'static str>,
+}
+
+impl QuoteState {
+    fn push(&mut self, html: &mut String, count: usize) {
+        // Extra apostrophes are literal, as in MediaWiki
+        let toggles: &[&'static str] = match count {
+            2 => &["i"],
+            3 => &["b"],
+            4 => {
+                html.push('\'');
+                &["b"]
+            }
+            _ => {
+                html.push_str(&"'".repeat(count - 5));
+                // Toggle the innermost open tag first, so closing both does not reopen one
+                if self.open.last() == Some(&"b") {
+                    &["b", "i"]
+                } else {
+                    &["i", "b"]
+                }
+            }
+        };
+        for &tag in toggles {
+            self.toggle(html, tag);
+        }
+    }
+
+    fn toggle(&mut self, html: &mut String, tag: &'static str) {
+        match self.open.iter().position(|&t| t == tag) {
+            Some(pos) => {
+                // Close the tags opened after this one and reopen them so the HTML stays nested
+                let reopen = self.open.split_off(pos + 1);
+                for t in reopen.iter().rev() {
+                    html.push_str(&format!("</{t}>"));
+                }
+                self.open.pop();
+                html.push_str(&format!("</{tag}>"));
+                for t in reopen {
+                    html.push_str(&format!("<{t}>"));
+                    self.open.push(t);
+                }
+            }
+            None => {
+                html.push_str(&format!("<{tag}>"));
+                self.open.push(tag);
+            }
+        }
+    }
+
+    fn close(&mut self, html: &mut String) {
+        while let Some(tag) = self.open.pop() {
+            html.push_str(&format!("</{tag}>"));
+        }
+    }
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    if let Some(hex) = entity
+        .strip_prefix("#x")
+        .or_else(|| entity.strip_prefix("#X"))
+    {
+        return u32::from_str_radix(hex, 16).ok().and_then(char::from_u32);
+    }
+    if let Some(decimal) = entity.strip_prefix('#') {
+        return decimal.parse().ok().and_then(char::from_u32);
+    }
+    let c = match entity {
+        "nbsp" => '\u{a0}',
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "ndash" => '–',
+        "mdash" => '—',
+        "minus" => '−',
+        "hellip" => '…',
+        "middot" => '·',
+        "bull" => '•',
+        "times" => '×',
+        "divide" => '÷',
+        "plusmn" => '±',
+        "deg" => '°',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "laquo" => '«',
+        "raquo" => '»',
+        "copy" => '©',
+        "reg" => '®',
+        "larr" => '←',
+        "rarr" => '→',
+        "harr" => '↔',
+        "uarr" => '↑',
+        "darr" => '↓',
+        "ensp" => '\u{2002}',
+        "emsp" => '\u{2003}',
+        "thinsp" => '\u{2009}',
+        "zwnj" => '\u{200c}',
+        "zwj" => '\u{200d}',
+        "shy" => '\u{ad}',
+        _ => return None,
+    };
+    Some(c)
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headings() {
+        assert_eq!(
+            wikitext_to_html("== 历史 ==\n文本\n=== [[早期]] ===\n= 一级 ="),
+            concat!(
+                "<h2>历史</h2><p>文本\n</p>",
+                "<h3><a href=\"/index.php/早期\" title=\"早期\">早期</a></h3>",
+                "<p>= 一级 =\n</p>",
+            )
+        );
+    }
+
+    #[test]
+    fn nested_lists() {
+        assert_eq!(
+            wikitext_to_html("* 甲\n** 乙\n*# 丙\n* 丁\n# 一\n# 二"),
+            concat!(
+                "<ul><li>甲<ul><li>乙</li></ul><ol><li>丙</li></ol></li><li>丁</li></ul>",
+                "<ol><li>一</li><li>二</li></ol>",
+            )
+        );
+        assert_eq!(
+            wikitext_to_html("; 术语 : 定义\n; [[Wikipedia:方针]]\n: 说明"),
+            concat!(
+                "<dl><dt>术语</dt><dd>定义</dd><dt><a href=\"/index.php/Wikipedia:方针\" ",
+                "title=\"Wikipedia:方针\">Wikipedia:方针</a></dt><dd>说明</dd></dl>",
+            )
+        );
+    }
+
+    #[test]
+    fn quotes_are_balanced() {
+        assert_eq!(
+            render_inline("''斜'' '''粗''' '''''都'''''"),
+            "<i>斜</i> <b>粗</b> <i><b>都</b></i>"
+        );
+        assert_eq!(
+            render_inline("'''粗''斜'''斜''"),
+            "<b>粗<i>斜</i></b><i>斜</i>"
+        );
+        assert_eq!(render_inline("''未闭合"), "<i>未闭合</i>");
+        assert_eq!(render_inline("''''四"), "'<b>四</b>");
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            render_inline("[[北京市|北京]]和[[上海 (城市)|]][[Category:城市]][[en:Beijing]]"),
+            concat!(
+                r#"<a href="/index.php/北京市" title="北京市">北京</a>和"#,
+                r#"<a href="/index.php/上海_(城市)" title="上海 (城市)">上海</a>"#,
+            )
+        );
+        assert_eq!(
+            render_inline("[https://example.org 例子] [https://example.org]"),
+            r#"<a class="external text" href="https://example.org">例子</a> "#
+        );
+    }
+
+    #[test]
+    fn tables() {
+        assert_eq!(
+            wikitext_to_html(concat!(
+                "{| class=\"wikitable\"\n|+ 标题\n! 甲 !! 乙\n|-\n| rowspan=2 | [[a|b]] || c\n",
+                "|-\n| style=\"x\" | d\n|}",
+            )),
+            concat!(
+                "<table><caption>标题</caption><tr><th>甲</th><th>乙</th></tr>",
+                "<tr><td rowspan=\"2\"><a href=\"/index.php/a\" title=\"a\">b</a></td><td>c</td></tr>",
+                "<tr><td>d</td></tr></table>",
+            )
+        );
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(
+            render_inline("a&nbsp;b &amp; &#x4E2D;&#25991; &lt;x&gt; &unknown; & c"),
+            "a\u{a0}b &amp; 中文 &lt;x&gt; &amp;unknown; &amp; c"
+        );
+    }
+}
@@ -0,0 +1,448 @@
+// Converts wikitext between Chinese variants like MediaWiki's LanguageConverter does,
+// with the tables of https://github.com/wikimedia/mediawiki/blob/master/includes/languages/data/ZhConversion.php
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+
+use crate::ZhVariant;
+
+/// Codes that can appear in `-{zh-hans:...;zh-hant:...}-` markup
+const VARIANT_CODES: &[&str] = &[
+    "zh", "zh-hans", "zh-hant", "zh-cn", "zh-hk", "zh-mo", "zh-my", "zh-sg", "zh-tw",
+];
+
+/// Replacement table with longest-match lookup
+#[derive(Debug, Default, Clone)]
+struct Table {
+    replacements: HashMap<String, String>,
+    /// Length in chars of the longest key starting with each char
+    max_len: HashMap<char, usize>,
+}
+
+impl Table {
+    fn insert(&mut self, from: &str, to: &str) {
+        let Some(first) = from.chars().next() else {
+            return;
+        };
+        let len = self.max_len.entry(first).or_default();
+        *len = (*len).max(from.chars().count());
+        self.replacements.insert(from.to_string(), to.to_string());
+    }
+
+    fn extend(&mut self, other: &Table) {
+        for (from, to) in &other.replacements {
+            self.insert(from, to);
+        }
+    }
+
+    /// Longest key at the start of `text` and its replacement, if any
+    fn longest_match(&self, text: &str) -> Option<(usize, &str)> {
+        let max_len = *self.max_len.get(&text.chars().next()?)?;
+        let mut ends: Vec<usize> = text
+            .char_indices()
+            .skip(1)
+            .map(|(i, _)| i)
+            .take(max_len)
+            .collect();
+        if ends.len() < max_len {
+            ends.push(text.len());
+        }
+        ends.iter().rev().find_map(|&end| {
+            self.replacements
+                .get(&text[..end])
+                .map(|to| (end, to.as_str()))
+        })
+    }
+}
+
+/// Converter built from the `$zh2Hans`, `$zh2Hant`, `$zh2CN`, `$zh2HK`, `$zh2SG` and `$zh2TW`
+/// tables of `ZhConversion.php`. Text in `zh` keeps its original variants.
+#[derive(Debug, Default)]
+pub struct Converter {
+    tables: HashMap<String, Table>,
+}
+
+impl Converter {
+    pub fn load(php_filename: &str) -> Result<Converter, Box<dyn std::error::Error>> {
+        let php = fs::read_to_string(php_filename)?;
+        let array =
+            Regex::new(r"(?s)\$(zh2\w+)\s*=\s*(?:\[|array\s*\()(.*?)(?:\]|\))\s*;").unwrap();
+        let entry = Regex::new(r"'((?:[^'\\]|\\.)*)'\s*=>\s*'((?:[^'\\]|\\.)*)'").unwrap();
+        let unescape = |s: &str| s.replace("\\'", "'").replace("\\\\", "\\");
+
+        let mut arrays: HashMap<String, Table> = HashMap::new();
+        for caps in array.captures_iter(&php) {
+            let table = arrays.entry(caps[1].to_string()).or_default();
+            for entry in entry.captures_iter(&caps[2]) {
+                table.insert(&unescape(&entry[1]), &unescape(&entry[2]));
+            }
+        }
+        if arrays.is_empty() {
+            return Err(format!("no conversion tables found in {php_filename}").into());
+        }
+
+        let merged = |names: &[&str]| {
+            let mut table = Table::default();
+            for name in names {
+                if let Some(array) = arrays.get(*name) {
+                    table.extend(array);
+                }
+            }
+            table
+        };
+        // Same combinations as MediaWiki's ZhConverter::loadDefaultTables
+        let tables = HashMap::from_iter([
+            ("zh-hans".to_string(), merged(&["zh2Hans"])),
+            ("zh-hant".to_string(), merged(&["zh2Hant"])),
+            ("zh-cn".to_string(), merged(&["zh2Hans", "zh2CN"])),
+            ("zh-hk".to_string(), merged(&["zh2Hant", "zh2HK"])),
+            ("zh-mo".to_string(), merged(&["zh2Hant", "zh2HK"])),
+            ("zh-my".to_string(), merged(&["zh2Hans", "zh2CN"])),
+            ("zh-sg".to_string(), merged(&["zh2Hans", "zh2SG"])),
+            ("zh-tw".to_string(), merged(&["zh2Hant", "zh2TW"])),
+        ]);
+        Ok(Converter { tables })
+    }
+
+    /// Convert wikitext into `variant`, applying `-{...}-` markup and article-wide rules.
+    /// Link targets and HTML tags are left as they are so links still point to the same page.
+    /// For `zh` only the markup is removed.
+    pub fn convert(&self, wikitext: &str, variant: ZhVariant) -> String {
+        if variant == ZhVariant::Zh {
+            return remove_markups(wikitext, false);
+        }
+        let code = variant.to_string();
+        let empty = Table::default();
+        let table = self.tables.get(&code).unwrap_or(&empty);
+
+        // Rules from `-{A|...}-` and `-{H|...}-` apply to the whole article, also before them
+        let mut rules = Table::default();
+        for markup in markups(wikitext) {
+            let (flags, content) = split_flags(markup);
+            if flags.contains(&"A") || flags.contains(&"H") {
+                if let Some(entries) = parse_rules(content) {
+                    add_rules(&mut rules, &entries, &code);
+                }
+            }
+        }
+
+        convert_text(wikitext, &code, table, &rules)
+    }
+}
+
+fn convert_text(wikitext: &str, code: &str, table: &Table, rules: &Table) -> String {
+    let mut output = String::with_capacity(wikitext.len());
+    let mut i = 0;
+    while i < wikitext.len() {
+        let rest = &wikitext[i..];
+        if rest.starts_with("-{") {
+            if let Some(end) = markup_end(rest) {
+                output.push_str(&render_markup(&rest[2..end - 2], code));
+                i += end;
+                continue;
+            }
+        } else if rest.starts_with("[[") {
+            if let Some(end) = rest.find("]]").filter(|&end| !rest[2..end].contains("[[")) {
+                let inner = &rest[2..end];
+                let (target, label) = match inner.split_once('|') {
+                    Some((target, label)) => (target, label),
+                    None => (inner, inner),
+                };
+                // Keep the target and show the converted title as the label
+                output.push_str("[[");
+                output.push_str(target);
+                output.push('|');
+                output.push_str(&convert_text(label, code, table, rules));
+                output.push_str("]]");
+                i += end + 2;
+                continue;
+            }
+        } else if rest.starts_with('<') {
+            if let Some(len) = html_tag_len(rest) {
+                output.push_str(&rest[..len]);
+                i += len;
+                continue;
+            }
+        }
+
+        // Article rules take precedence over the conversion tables
+        match rules
+            .longest_match(rest)
+            .or_else(|| table.longest_match(rest))
+        {
+            Some((len, to)) => {
+                output.push_str(to);
+                i += len;
+            }
+            None => {
+                let c = rest.chars().next().unwrap();
+                output.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+    output
+}
+
+/// Replace each `-{...}-` markup with what it shows without conversion: its text, or the `zh`
+/// text of a rule markup. With `first`, rule markups without a `zh` text show their first text
+/// instead of nothing.
+pub fn remove_markups(wikitext: &str, first: bool) -> String {
+    let mut output = String::with_capacity(wikitext.len());
+    let mut i = 0;
+    while let Some(start) = wikitext[i..].find("-{") {
+        let start = i + start;
+        let Some(end) = markup_end(&wikitext[start..]) else {
+            break;
+        };
+        output.push_str(&wikitext[i..start]);
+        let (flags, content) = split_flags(&wikitext[start + 2..start + end - 2]);
+        if !flags
+            .iter()
+            .any(|flag| matches!(*flag, "H" | "T" | "-" | "D"))
+        {
+            match parse_rules(content) {
+                _ if flags.contains(&"R") => output.push_str(content),
+                Some(rules) => {
+                    let mut bidirectional = rules.iter().filter(|rule| rule.from.is_none());
+                    let text = match bidirectional.clone().find(|rule| rule.variant == "zh") {
+                        Some(rule) => Some(rule),
+                        None if first => bidirectional.next(),
+                        None => None,
+                    };
+                    output.extend(text.map(|rule| rule.text));
+                }
+                None => output.push_str(content),
+            }
+        }
+        i = start + end;
+    }
+    output.push_str(&wikitext[i..]);
+    output
+}
+
+/// Length of the HTML tag at the start of `text`, which must not be converted
+fn html_tag_len(text: &str) -> Option<usize> {
+    let name = text.strip_prefix('<')?.trim_start_matches('/');
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let end = text[1..].find(['<', '>'])? + 1;
+    text[end..].starts_with('>').then_some(end + 1)
+}
+
+/// Variants to try, in order, when markup has no text for `code`, as in MediaWiki's `LanguageZh`
+fn fallbacks(code: &str) -> &'static [&'static str] {
+    match code {
+        "zh-hans" => &["zh-hans", "zh-cn", "zh-sg", "zh-my"],
+        "zh-hant" => &["zh-hant", "zh-tw", "zh-hk", "zh-mo"],
+        "zh-cn" => &["zh-cn", "zh-hans", "zh-sg", "zh-my"],
+        "zh-sg" => &["zh-sg", "zh-my", "zh-hans", "zh-cn"],
+        "zh-my" => &["zh-my", "zh-sg", "zh-hans", "zh-cn"],
+        "zh-tw" => &["zh-tw", "zh-hant", "zh-hk", "zh-mo"],
+        "zh-hk" => &["zh-hk", "zh-mo", "zh-hant", "zh-tw"],
+        "zh-mo" => &["zh-mo", "zh-hk", "zh-hant", "zh-tw"],
+        _ => &[
+            "zh", "zh-hans", "zh-hant", "zh-cn", "zh-tw", "zh-hk", "zh-sg", "zh-mo", "zh-my",
+        ],
+    }
+}
+
+/// Byte index one past the `}-` closing the `-{` at the start of `text`
+fn markup_end(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        if rest.starts_with("-{") {
+            depth += 1;
+            i += 2;
+        } else if rest.starts_with("}-") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return Some(i);
+            }
+        } else {
+            i += rest.chars().next().unwrap().len_utf8();
+        }
+    }
+    None
+}
+
+/// Contents of all top-level `-{...}-` markups
+fn markups(text: &str) -> Vec<&str> {
+    let mut markups = vec![];
+    let mut i = 0;
+    while let Some(start) = text[i..].find("-{") {
+        let start = i + start;
+        match markup_end(&text[start..]) {
+            Some(end) => {
+                markups.push(&text[start + 2..start + end - 2]);
+                i = start + end;
+            }
+            None => break,
+        }
+    }
+    markups
+}
+
+/// Split `A|zh-hans:...` into flags and content, if the part before `|` is a list of flags
+fn split_flags(markup: &str) -> (Vec<&str>, &str) {
+    if let Some((flags, content)) = markup.split_once('|') {
+        let flags: Vec<&str> = flags.split(';').map(str::trim).collect();
+        let valid = flags.iter().all(|flag| {
+            matches!(*flag, "A" | "T" | "R" | "D" | "-" | "H" | "N") || VARIANT_CODES.contains(flag)
+        });
+        if valid {
+            return (flags, content);
+        }
+    }
+    (vec![], markup)
+}
+
+/// A rule `variant:text`, or `from=>variant:text` if it only applies to `from`
+struct Rule<'a> {
+    from: Option<&'a str>,
+    variant: &'a str,
+    text: &'a str,
+}
+
+/// Parse `zh-hans:计算机;zh-hant:電腦;`, or `None` if the content is plain text
+fn parse_rules(content: &str) -> Option<Vec<Rule<'_>>> {
+    let mut rules = vec![];
+    for entry in content.split(';').filter(|entry| !entry.trim().is_empty()) {
+        let (from, entry) = match entry.split_once("=>") {
+            Some((from, entry)) => (Some(from.trim()), entry),
+            None => (None, entry),
+        };
+        let (variant, text) = entry.split_once(':')?;
+        let variant = variant.trim();
+        if !VARIANT_CODES.contains(&variant) {
+            return None;
+        }
+        rules.push(Rule {
+            from,
+            variant,
+            text: text.trim(),
+        });
+    }
+    if rules.is_empty() {
+        None
+    } else {
+        Some(rules)
+    }
+}
+
+/// Text of the rules for `code`, falling back to related variants and then the first rule
+fn pick<'a>(rules: &[Rule<'a>], code: &str) -> &'a str {
+    let bidirectional: Vec<&Rule> = rules.iter().filter(|rule| rule.from.is_none()).collect();
+    fallbacks(code)
+        .iter()
+        .find_map(|code| bidirectional.iter().find(|rule| rule.variant == *code))
+        .or(bidirectional.first())
+        .map_or("", |rule| rule.text)
+}
+
+fn add_rules(table: &mut Table, rules: &[Rule], code: &str) {
+    let target = pick(rules, code);
+    for rule in rules {
+        match rule.from {
+            Some(from) if fallbacks(code).contains(&rule.variant) => table.insert(from, rule.text),
+            Some(_) => {}
+            None if !target.is_empty() => table.insert(rule.text, target),
+            None => {}
+        }
+    }
+}
+
+/// What a `-{...}-` markup shows in the `code` variant
+fn render_markup(markup: &str, code: &str) -> String {
+    let (flags, content) = split_flags(markup);
+    if flags
+        .iter()
+        .any(|flag| matches!(*flag, "H" | "T" | "-" | "D"))
+    {
+        return String::new();
+    }
+    if flags.contains(&"R") {
+        return content.to_string();
+    }
+    match parse_rules(content) {
+        Some(rules) if rules.iter().any(|rule| rule.from.is_none()) => {
+            pick(&rules, code).to_string()
+        }
+        Some(_) => String::new(),
+        // Plain text that must not be converted
+        None => content.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn converter() -> Converter {
+        let mut hans = Table::default();
+        hans.insert("電腦", "电脑");
+        hans.insert("這", "这");
+        hans.insert("台", "台");
+        let mut hant = Table::default();
+        hant.insert("电脑", "電腦");
+        Converter {
+            tables: HashMap::from_iter([
+                ("zh-hans".to_string(), hans),
+                ("zh-hant".to_string(), hant),
+            ]),
+        }
+    }
+
+    #[test]
+    fn longest_match() {
+        let mut table = Table::default();
+        table.insert("计", "計");
+        table.insert("计算机", "電腦");
+        assert_eq!(table.longest_match("计算机很快"), Some((9, "電腦")));
+        assert_eq!(table.longest_match("计算"), Some((3, "計")));
+        assert_eq!(table.longest_match("算"), None);
+        assert_eq!(table.longest_match(""), None);
+    }
+
+    #[test]
+    fn zh_only_removes_markup() {
+        let converter = converter();
+        let text = "-{A|zh-hans:计算机;zh-hant:電腦}-這台電腦。";
+        assert_eq!(converter.convert(text, ZhVariant::Zh), "這台電腦。");
+        assert_eq!(
+            converter.convert(
+                "-{zh:電腦;zh-hans:计算机}-，-{電腦}--{H|zh-hans:a;zh-hant:b}-",
+                ZhVariant::Zh
+            ),
+            "電腦，電腦"
+        );
+    }
+
+    #[test]
+    fn markup() {
+        let converter = converter();
+        let text = "-{A|zh-hans:计算机;zh-hant:電腦}-這台電腦。";
+        assert_eq!(
+            converter.convert(text, ZhVariant::Hans),
+            "计算机这台计算机。"
+        );
+        assert_eq!(converter.convert(text, ZhVariant::Hant), "電腦這台電腦。");
+        // Plain markup is not converted, and link targets are kept
+        assert_eq!(
+            converter.convert(
+                "-{電腦}-[[電腦]]<span title=\"電腦\">電腦</span>",
+                ZhVariant::Hans
+            ),
+            "電腦[[電腦|电脑]]<span title=\"電腦\">电脑</span>"
+        );
+        // Rules only for one source text
+        assert_eq!(
+            converter.convert("-{H|電腦=>zh-hans:计算机;}-電腦", ZhVariant::Hans),
+            "计算机"
+        );
+    }
+}